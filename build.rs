@@ -0,0 +1,7 @@
+use std::env;
+
+fn main() {
+    // The triple this is built for, which `get_host_triple` falls back to
+    // when there's no rustc to ask.
+    println!("cargo:rustc-env=BISECT_BUILD_TARGET={}", env::var("TARGET").unwrap());
+}
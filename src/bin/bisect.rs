@@ -3,7 +3,13 @@
 #[macro_use] extern crate error_chain;
 #[macro_use] extern crate clap;
 #[macro_use] extern crate log;
+#[macro_use] extern crate serde_derive;
+extern crate chrono;
+extern crate ctrlc;
 extern crate env_logger;
+extern crate libc;
+extern crate reqwest;
+extern crate serde_json;
 extern crate rust_sysroot;
 
 mod errors {
@@ -22,77 +28,2457 @@ use errors::*;
 
 quick_main!(run);
 
-use std::path::Path;
+use std::cell::Cell;
+use std::cmp;
+use std::env;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
 
-use rust_sysroot::git::Commit;
-use rust_sysroot::sysroot::Sysroot;
-use rust_sysroot::{get_host_triple, EPOCH_COMMIT};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::ArgMatches;
+use reqwest::Client;
 
-// return true if commit is successfully broken
-fn test_commit(commit: &Commit, test_case: &Path, triple: &str, preserve_sysroots: bool) -> Result<bool> {
-    let sysroot = Sysroot::install(commit, triple, preserve_sysroots, false)?;
+use rust_sysroot::git::{self, Commit};
+use rust_sysroot::github::{self, PullRequest};
+use rust_sysroot::nightly;
+use rust_sysroot::overrides;
+use rust_sysroot::sysroot::{self, DownloadedFrom, Sysroot};
+use rust_sysroot::{check_chronological, get_host_triple, least_satisfying, try_least_satisfying_skipping, Probe,
+                   Transition, EPOCH_COMMIT};
 
-    let status = sysroot.command(test_case).status()?;
-    info!("tested {:} from {}: test failed: {}", &commit.sha[0..9], commit.date.to_rfc2822(), status.success());
-    Ok(status.success())
+/// The result of testing a single commit, split by the stage that failed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum TestOutcome {
+    /// The build stage of the test exited with an error or could not be run.
+    BuildFailed,
+    /// The test built, but the run stage exited with an error.
+    RunFailed,
+    /// The test script, run once rather than in stages, exited with an error
+    /// or could not be run.
+    Failed,
+    /// Both stages succeeded.
+    Passed,
+    /// One of the stages was killed for running longer than `--timeout`.
+    TimedOut,
+    /// Both stages succeeded, but the measurement exceeded the `--perf`
+    /// threshold.
+    Slow,
+    /// No artifacts were uploaded for the commit, so it couldn't be tested;
+    /// only with `--skip-missing`. Also for commits skipped with `--manual`,
+    /// or by the test printing `skip`.
+    Missing,
 }
 
-/// Finds the index of the least item in `slice` for which the `predicate` holds.
-pub fn least_satisfying<T, P>(slice: &[T], mut predicate: P) -> usize
-    where P: FnMut(&T) -> bool
+/// How a stage of the test ended.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum StageResult {
+    Passed,
+    Failed,
+    TimedOut,
+    /// The test printed `skip`, as it can't tell for this commit.
+    Skipped,
+}
+
+/// A line the test script may print on stdout to say how a stage went,
+/// rather than only through its exit status, which it overrides.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Verdict {
+    Good,
+    Bad,
+    Skip,
+    Abort,
+}
+
+impl Verdict {
+    fn parse(line: &str) -> Option<Verdict> {
+        match line.trim() {
+            "good" => Some(Verdict::Good),
+            "bad" => Some(Verdict::Bad),
+            "skip" => Some(Verdict::Skip),
+            "abort" => Some(Verdict::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// What the test printed on stdout for its stage: the last verdict, and the
+/// last `metric: <number>` line, which `--perf` measures by.
+#[derive(Debug, Copy, Clone, Default)]
+struct Printed {
+    verdict: Option<Verdict>,
+    metric: Option<f64>,
+}
+
+impl Printed {
+    fn parse(line: &str) -> Printed {
+        let metric = if line.trim().starts_with("metric:") {
+            line.trim()["metric:".len()..].trim().parse().ok()
+        } else {
+            None
+        };
+        Printed { verdict: Verdict::parse(line), metric }
+    }
+}
+
+/// How long to wait for the rest of the test's output once it exited, in
+/// case something it started in the background still holds on to stdout.
+const VERDICT_WAIT: Duration = Duration::from_secs(1);
+
+/// What testing a commit consists of.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TestKind {
+    /// Running the `--test` script once, as `test_case`.
+    Script,
+    /// Running the `--test` script as `test_case build`, then as
+    /// `test_case run`, with `--stages`.
+    StagedScript,
+    /// Running the `--test` program once with the `--test-arg`s, which is
+    /// the build stage; see `Tester::test_command`.
+    Command,
+    /// Documenting the `--rustdoc` file or crate, which is the build stage;
+    /// see `Tester::rustdoc_command`.
+    Rustdoc,
+    /// Asking the user; see `Tester::ask`.
+    Manual,
+    /// Compiling the `--src` file with rustc, and running it for
+    /// `Expect::RunPass`; see `Tester::source_command`.
+    Source(Expect),
+}
+
+/// What should happen to the `--src` file on a working commit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Expect {
+    CompilePass,
+    CompileFail,
+    RunPass,
+}
+
+impl FromStr for Expect {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Expect> {
+        match s {
+            "compile-pass" => Ok(Expect::CompilePass),
+            "compile-fail" => Ok(Expect::CompileFail),
+            "run-pass" => Ok(Expect::RunPass),
+            _ => bail!("unknown expectation: {}", s),
+        }
+    }
+}
+
+/// The exit code of rustc when it reports errors, as opposed to e.g. 101 for
+/// an ICE.
+const RUSTC_ERROR_EXIT_CODE: i32 = 1;
+
+impl TestKind {
+    fn has_run_stage(&self) -> bool {
+        matches!(*self, TestKind::StagedScript | TestKind::Source(Expect::RunPass))
+    }
+
+    // Whether a stage which exited with `status` passed. That's the case if
+    // it succeeded, except that compiling a `compile-fail` source file has
+    // to fail with errors rather than an ICE.
+    fn stage_passed(&self, stage: &str, status: &ExitStatus) -> bool {
+        match *self {
+            TestKind::Source(Expect::CompileFail) if stage == "build" =>
+                status.code() == Some(RUSTC_ERROR_EXIT_CODE),
+            _ => status.success(),
+        }
+    }
+}
+
+/// Which test outcomes count as the commit being broken.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum RegressionKind {
+    Build,
+    Run,
+    Any,
+}
+
+impl FromStr for RegressionKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RegressionKind> {
+        match s {
+            "build" => Ok(RegressionKind::Build),
+            "run" => Ok(RegressionKind::Run),
+            "any" => Ok(RegressionKind::Any),
+            _ => bail!("unknown regression kind: {}", s),
+        }
+    }
+}
+
+impl RegressionKind {
+    fn is_broken(&self, outcome: TestOutcome) -> bool {
+        match (*self, outcome) {
+            (_, TestOutcome::Passed) => false,
+            (_, TestOutcome::Slow) => true,
+            (RegressionKind::Build, TestOutcome::BuildFailed) => true,
+            (RegressionKind::Run, TestOutcome::RunFailed) => true,
+            (RegressionKind::Any, TestOutcome::BuildFailed) => true,
+            (RegressionKind::Any, TestOutcome::RunFailed) => true,
+            (RegressionKind::Any, TestOutcome::Failed) => true,
+            _ => false,
+        }
+    }
+}
+
+/// How the results of running the test several times on a commit are
+/// combined, to cope with flaky tests.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Quorum {
+    /// The commit is broken if any run was, e.g. for intermittent crashes.
+    Any,
+    /// The commit is only broken if every run was.
+    All,
+    /// The commit is broken if most runs were.
+    Majority,
+}
+
+impl FromStr for Quorum {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Quorum> {
+        match s {
+            "any" => Ok(Quorum::Any),
+            "all" => Ok(Quorum::All),
+            "majority" => Ok(Quorum::Majority),
+            _ => bail!("unknown quorum: {}", s),
+        }
+    }
+}
+
+impl Quorum {
+    /// Whether the commit is broken, given which of its runs were.
+    fn is_broken(&self, broken: &[bool], runs: usize) -> bool {
+        let count = broken.iter().filter(|&&broken| broken).count();
+        match *self {
+            Quorum::Any => count > 0,
+            Quorum::All => count == broken.len(),
+            Quorum::Majority => count * 2 > runs,
+        }
+    }
+
+    /// Whether the remaining runs, out of `runs`, can no longer change the
+    /// result.
+    fn is_decided(&self, broken: &[bool], runs: usize) -> bool {
+        let count = broken.iter().filter(|&&broken| broken).count();
+        let passed = broken.len() - count;
+        match *self {
+            Quorum::Any => count > 0,
+            Quorum::All => passed > 0,
+            Quorum::Majority => count * 2 > runs || passed * 2 >= runs,
+        }
+    }
+}
+
+/// Whether to look for the commit which broke the test, or the one which fixed
+/// it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Find {
+    Regression,
+    Fix,
+}
+
+impl FromStr for Find {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Find> {
+        match s {
+            "regression" => Ok(Find::Regression),
+            "fix" => Ok(Find::Fix),
+            _ => bail!("unknown kind of commit to find: {}", s),
+        }
+    }
+}
+
+impl Find {
+    /// The state of the test after the commit that is looked for.
+    fn state(&self) -> &'static str {
+        match *self {
+            Find::Regression => "broken",
+            Find::Fix => "fixed",
+        }
+    }
+}
+
+// Runs `command` to completion, unless `timeout` passes first; then it's
+// killed along with everything it spawned, and `None` is returned. Given
+// somewhere to pass its stdout on to, what it printed there is returned too.
+fn run_with_timeout(command: &mut Command, timeout: Option<Duration>, stdout: Option<Box<dyn Write + Send>>)
+                    -> io::Result<(Option<ExitStatus>, Printed)> {
+    // Put the test in its own process group, so that cargo, rustc and
+    // whatever else it started can be killed together with it.
+    #[cfg(unix)]
+    unsafe {
+        if timeout.is_some() {
+            command.pre_exec(|| {
+                libc::setpgid(0, 0);
+                Ok(())
+            });
+        }
+    }
+    if stdout.is_some() {
+        command.stdout(Stdio::piped());
+    }
+
+    let mut child = command.spawn()?;
+    let printed = match (child.stdout.take(), stdout) {
+        (Some(piped), Some(stdout)) => Some(pass_on_printed(piped, stdout)),
+        _ => None,
+    };
+    let status = match timeout {
+        None => Some(child.wait()?),
+        Some(timeout) => {
+            let start = Instant::now();
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break Some(status);
+                }
+                if start.elapsed() >= timeout {
+                    kill(&mut child);
+                    child.wait()?;
+                    break None;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    };
+    Ok((status, printed.map(last_printed).unwrap_or_default()))
+}
+
+// Passes the lines of `piped` on to `stdout` on a thread of its own, sending
+// each of them which is a verdict or a metric.
+fn pass_on_printed(piped: ChildStdout, mut stdout: Box<dyn Write + Send>) -> Receiver<Printed> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut piped = io::BufReader::new(piped);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match piped.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let _ = stdout.write_all(&line).and_then(|_| stdout.flush());
+            let printed = Printed::parse(&String::from_utf8_lossy(&line));
+            if printed.verdict.is_some() || printed.metric.is_some() {
+                let _ = sender.send(printed);
+            }
+        }
+    });
+    receiver
+}
+
+// The last verdict and metric sent by `pass_on_printed`, once the output
+// ended or nothing more came for `VERDICT_WAIT`.
+fn last_printed(lines: Receiver<Printed>) -> Printed {
+    let mut last = Printed::default();
+    while let Ok(printed) = lines.recv_timeout(VERDICT_WAIT) {
+        last.verdict = printed.verdict.or(last.verdict);
+        last.metric = printed.metric.or(last.metric);
+    }
+    last
+}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// How to test each commit, and which results count as broken.
+#[derive(Clone)]
+struct Tester {
+    test_case: PathBuf,
+    /// The arguments to run the test program with, for `TestKind::Command`.
+    test_args: Vec<String>,
+    kind: TestKind,
+    /// The triples to test each commit for; see `Tester::test`.
+    triples: Vec<String>,
+    preserve_sysroots: bool,
+    config: sysroot::Config,
+    regression_kind: RegressionKind,
+    find: Find,
+    /// How long each stage of the test may run before it's killed.
+    timeout: Option<Duration>,
+    /// Whether a test that timed out counts as broken.
+    timeout_is_regression: bool,
+    /// Whether to write the output of the test to a log file per commit.
+    capture: bool,
+    /// Whether to check each sysroot with `Sysroot::verify` before testing.
+    verify_sysroot: bool,
+    /// Whether to skip commits without artifacts, rather than stopping.
+    skip_missing: bool,
+    /// How many times to run the test for each commit, and how to combine
+    /// the results of those runs.
+    runs: usize,
+    quorum: Quorum,
+    /// The file to append a `StepLog` line to for every tested commit.
+    log_file: Option<PathBuf>,
+    /// Whether to print where the time of each step went.
+    verbose_timing: bool,
+    /// The time spent running tests so far, for `verbose_timing`.
+    test_time: Cell<Duration>,
+    /// The directory to give each commit a directory of its own in, for the
+    /// test to keep its artifacts in.
+    output_dir: Option<PathBuf>,
+    /// A script which builds or locates a rustc for each commit, to use
+    /// instead of the one from CI; see `Tester::local_rustc`.
+    local_rustc: Option<PathBuf>,
+    /// With `--perf`, the measurement above which a commit counts as broken;
+    /// see `Tester::run_once`.
+    perf_threshold: Option<f64>,
+}
+
+/// A line of the `--log-file`, recording how testing one commit went.
+#[derive(Serialize)]
+struct StepLog<'a> {
+    sha: &'a str,
+    triple: &'a str,
+    date: DateTime<Utc>,
+    outcome: TestOutcome,
+    /// How long installing the sysroot and testing took.
+    duration_secs: f64,
+    /// How many archives were used from the cache and how many downloaded
+    /// meanwhile, which includes any prefetches running alongside.
+    cache_hits: usize,
+    downloads: usize,
+    /// How long was spent downloading, extracting and running the test, see
+    /// `sysroot::DownloadStats`.
+    download_secs: f64,
+    extract_secs: f64,
+    test_secs: f64,
+    /// With `--perf`, the measurement of the commit; see `Tester::run_once`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    measurement: Option<f64>,
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_millis()) / 1000.0
+}
+
+fn is_missing_artifacts(err: &rust_sysroot::errors::Error) -> bool {
+    matches!(*err.kind(), rust_sysroot::errors::ErrorKind::MissingArtifacts(..))
+}
+
+/// The exit code with which a stage of the test script aborts the whole
+/// bisection, e.g. because it's missing a dependency, rather than passing or
+/// failing.
+const ABORT_EXIT_CODE: i32 = 125;
+
+impl Tester {
+    /// The log the output of testing `commit` is captured in. These are kept
+    /// apart from the sysroots, so they survive even if those are deleted.
+    fn log_path(&self, commit: &Commit, triple: &str) -> PathBuf {
+        self.config.cache_dir.join("logs").join(format!("{}.log", self.file_name(commit, triple)))
+    }
+
+    // The name of the log or output directory of testing `commit`, which only
+    // includes the triple if there are several.
+    fn file_name(&self, commit: &Commit, triple: &str) -> String {
+        if self.triples.len() == 1 {
+            commit.sha[0..9].to_string()
+        } else {
+            format!("{}-{}", &commit.sha[0..9], triple)
+        }
+    }
+
+    fn create_log(&self, commit: &Commit, triple: &str) -> Result<File> {
+        let path = self.log_path(commit, triple);
+        fs::create_dir_all(path.parent().unwrap())?;
+        File::create(&path).chain_err(|| format!("failed to create log {}", path.display()))
+    }
+
+    // Whether the test is a program of the user's, which may print a
+    // `Verdict`, rather than a tool of the sysroot or the compiled source.
+    fn prints_verdicts(&self) -> bool {
+        match self.kind {
+            TestKind::Script | TestKind::StagedScript => true,
+            TestKind::Command => {
+                !matches!(self.test_case.to_str(), Some("cargo") | Some("rustc") | Some("rustdoc"))
+            }
+            _ => false,
+        }
+    }
+
+    fn spawn_stage(&self, sysroot: &Sysroot, stage: &str, log: Option<&File>)
+                   -> io::Result<(Option<ExitStatus>, Printed)> {
+        let mut command = match self.kind {
+            TestKind::Rustdoc => self.rustdoc_command(sysroot)?,
+            TestKind::Source(_) => self.source_command(sysroot, stage)?,
+            TestKind::Command => self.test_command(sysroot)?,
+            TestKind::StagedScript => {
+                let mut command = sysroot.command(&self.test_case);
+                command.arg(stage);
+                command
+            }
+            _ => sysroot.command(&self.test_case),
+        };
+        if let Some(mut log) = log {
+            if self.kind == TestKind::Command {
+                writeln!(log, "==> {} {}", self.test_case.display(), self.test_args.join(" "))?;
+            } else if self.kind == TestKind::Script {
+                writeln!(log, "==> {}", self.test_case.display())?;
+            } else {
+                writeln!(log, "==> {} {}", self.test_case.display(), stage)?;
+            }
+            command.stdout(log.try_clone()?).stderr(log.try_clone()?);
+        }
+        let stdout: Option<Box<dyn Write + Send>> = if !self.prints_verdicts() {
+            None
+        } else if let Some(log) = log {
+            Some(Box::new(log.try_clone()?))
+        } else {
+            Some(Box::new(io::stdout()))
+        };
+        run_with_timeout(&mut command, self.timeout, stdout)
+    }
+
+    // Runs the `--test` program with the `--test-arg`s. `cargo`, `rustc` and
+    // `rustdoc` stand for the sysroot's own, and any other bare name is looked
+    // up in `PATH`.
+    fn test_command(&self, sysroot: &Sysroot) -> io::Result<Command> {
+        let program = match self.test_case.to_str() {
+            Some("cargo") => match sysroot.cargo {
+                Some(ref cargo) => cargo.clone(),
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, "cargo was not installed")),
+            },
+            Some("rustc") => sysroot.rustc.clone(),
+            Some("rustdoc") => sysroot.rustdoc.clone(),
+            _ => self.test_case.clone(),
+        };
+        let mut command = sysroot.command(&program);
+        command.args(&self.test_args);
+        Ok(command)
+    }
+
+    // Documents the `--rustdoc` file with rustdoc, or the crate in that
+    // directory with `cargo doc`. The output goes into the sysroot, so it's
+    // removed along with it.
+    fn rustdoc_command(&self, sysroot: &Sysroot) -> io::Result<Command> {
+        let out_dir = sysroot.cache_dir.join(&sysroot.sha).join("rustdoc");
+        if !self.test_case.is_dir() {
+            let mut command = sysroot.rustdoc_command();
+            command.arg(&self.test_case).arg("-o").arg(&out_dir);
+            return Ok(command);
+        }
+        let mut command = match sysroot.cargo_command() {
+            Some(command) => command,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "cargo was not installed")),
+        };
+        command.arg("doc").arg("--target-dir").arg(&out_dir).current_dir(&self.test_case);
+        Ok(command)
+    }
+
+    // Compiles the `--src` file with rustc in the build stage, and runs the
+    // program in the run stage. Both go into the sysroot, like the rustdoc
+    // output.
+    fn source_command(&self, sysroot: &Sysroot, stage: &str) -> io::Result<Command> {
+        let dir = sysroot.cache_dir.join(&sysroot.sha).join("src");
+        let bin = dir.join(format!("main{}", env::consts::EXE_SUFFIX));
+        if stage == "run" {
+            return Ok(sysroot.command(&bin));
+        }
+        fs::create_dir_all(&dir)?;
+        let mut command = sysroot.rustc_command();
+        command.arg(&self.test_case).arg("-o").arg(&bin).current_dir(&dir);
+        Ok(command)
+    }
+
+    // Runs one stage of the test script, i.e. `test_case build` or
+    // `test_case run`. A `Verdict` it printed decides the result, and
+    // otherwise its exit status. Fails if the script asked to abort the
+    // bisection. Also returns the metric the stage printed, or else how many
+    // seconds it took.
+    fn run_stage(&self, sysroot: &Sysroot, stage: &str, log: Option<&File>) -> Result<(StageResult, f64)> {
+        let start = Instant::now();
+        let (status, printed) = match self.spawn_stage(sysroot, stage, log) {
+            Ok(ran) => ran,
+            Err(err) => {
+                warn!("failed to run {} stage of {}: {}", stage, self.test_case.display(), err);
+                return Ok((StageResult::Failed, secs(start.elapsed())));
+            }
+        };
+        let measurement = printed.metric.unwrap_or_else(|| secs(start.elapsed()));
+        let result = match (status, printed.verdict) {
+            (Some(ref status), _) if status.code() == Some(ABORT_EXIT_CODE) => {
+                bail!("{} stage of {} exited with {}, aborting the bisection",
+                    stage, self.test_case.display(), ABORT_EXIT_CODE);
+            }
+            (_, Some(Verdict::Abort)) => {
+                bail!("{} stage of {} printed abort, aborting the bisection", stage, self.test_case.display());
+            }
+            (None, _) => {
+                warn!("{} stage of {} timed out", stage, self.test_case.display());
+                StageResult::TimedOut
+            }
+            (Some(_), Some(Verdict::Good)) => StageResult::Passed,
+            (Some(_), Some(Verdict::Bad)) => StageResult::Failed,
+            (Some(_), Some(Verdict::Skip)) => {
+                info!("{} stage of {} printed skip", stage, self.test_case.display());
+                StageResult::Skipped
+            }
+            (Some(status), None) if self.kind.stage_passed(stage, &status) => StageResult::Passed,
+            (Some(_), None) => StageResult::Failed,
+        };
+        Ok((result, measurement))
+    }
+
+    /// Tests `commit` for every triple. It only counts as found if it is for
+    /// all of them, so the outcome is that of a triple it isn't found for,
+    /// if any. With `--skip-missing`, it's skipped if any triple has no
+    /// artifacts.
+    ///
+    /// With `--perf`, also returns the measurement of the commit, the largest
+    /// one if it was measured for several triples.
+    fn test(&self, commit: &Commit) -> Result<(TestOutcome, Option<f64>)> {
+        let mut outcomes = Vec::new();
+        let mut largest: Option<f64> = None;
+        for triple in &self.triples {
+            let (outcome, measurement) = self.test_triple(commit, triple)?;
+            if outcome == TestOutcome::Missing {
+                return Ok((outcome, None));
+            }
+            outcomes.push((triple, outcome));
+            if let Some(measurement) = measurement {
+                largest = Some(largest.map_or(measurement, |largest| largest.max(measurement)));
+            }
+        }
+        let not_found = outcomes.iter().find(|&&(_, outcome)| self.is_found(outcome) == Some(false));
+        if not_found.is_some() && outcomes.iter().any(|&(_, outcome)| self.is_found(outcome) == Some(true)) {
+            warn!("the triples disagree on {}: {}", &commit.sha[0..9], outcomes.iter()
+                .map(|&(triple, outcome)| format!("{:?} for {}", outcome, triple))
+                .collect::<Vec<_>>().join(", "));
+        }
+        Ok((not_found.unwrap_or(&outcomes[0]).1, largest))
+    }
+
+    fn test_triple(&self, commit: &Commit, triple: &str) -> Result<(TestOutcome, Option<f64>)> {
+        let start = Instant::now();
+        let stats = sysroot::download_stats();
+        let (outcome, measurement, tested) = self.install_and_test(commit, triple)?;
+        let measurement = measurement.filter(|_| self.perf_threshold.is_some());
+        let duration = start.elapsed();
+        let after = sysroot::download_stats();
+        self.test_time.set(self.test_time.get() + tested);
+        if self.verbose_timing {
+            eprintln!("{} for {} took {:.1}s: {:.1}s downloading, {:.1}s extracting, {:.1}s testing",
+                &commit.sha[0..9], triple, secs(duration), secs(after.download_time - stats.download_time),
+                secs(after.extract_time - stats.extract_time), secs(tested));
+        }
+        if let Some(ref path) = self.log_file {
+            let step = StepLog {
+                sha: &commit.sha,
+                triple,
+                date: commit.date,
+                outcome,
+                duration_secs: secs(duration),
+                cache_hits: after.cache_hits - stats.cache_hits,
+                downloads: after.downloads - stats.downloads,
+                download_secs: secs(after.download_time - stats.download_time),
+                extract_secs: secs(after.extract_time - stats.extract_time),
+                test_secs: secs(tested),
+                measurement,
+            };
+            // Each line goes out in a single append, so that lines from
+            // resumed sessions never end up interleaved.
+            let line = serde_json::to_string(&step).unwrap() + "\n";
+            OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| file.write_all(line.as_bytes()))
+                .chain_err(|| format!("failed to write to {}", path.display()))?;
+        }
+        Ok((outcome, measurement))
+    }
+
+    // Runs the `--local-rustc` script as `script <sha>`, which builds or
+    // locates a rustc for the commit and prints its path as the last line of
+    // its output.
+    fn local_rustc(&self, script: &Path, commit: &Commit) -> Result<String> {
+        info!("getting a local rustc for {} from {}", &commit.sha[0..9], script.display());
+        let output = Command::new(script).arg(&commit.sha).stderr(Stdio::inherit()).output()
+            .chain_err(|| format!("failed to run {}", script.display()))?;
+        if !output.status.success() {
+            bail!("{} {} failed: {}", script.display(), commit.sha, output.status);
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match stdout.lines().map(str::trim).rfind(|line| !line.is_empty()) {
+            Some(rustc) => Ok(rustc.to_string()),
+            None => bail!("{} {} did not print the path of a rustc", script.display(), commit.sha),
+        }
+    }
+
+    // Installs the sysroot of `commit` and tests it, returning the outcome,
+    // the measurement with `--perf`, and how long running the test took.
+    fn install_and_test(&self, commit: &Commit, triple: &str) -> Result<(TestOutcome, Option<f64>, Duration)> {
+        let installed = match self.local_rustc {
+            Some(ref script) => {
+                let rustc = self.local_rustc(script, commit)?;
+                Sysroot::with_local_rustc(commit, &rustc, triple, self.preserve_sysroots, false, &self.config)
+            }
+            None => Sysroot::install(commit, triple, self.preserve_sysroots, false, &self.config),
+        };
+        let mut sysroot = match installed {
+            Ok(sysroot) => sysroot,
+            Err(ref err) if self.skip_missing && is_missing_artifacts(err) => {
+                warn!("skipping {}: {}", &commit.sha[0..9], err);
+                return Ok((TestOutcome::Missing, None, Duration::default()));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if self.verify_sysroot {
+            sysroot.verify()?;
+        }
+        if let Some(ref output_dir) = self.output_dir {
+            let dir = output_dir.join(self.file_name(commit, triple));
+            fs::create_dir_all(&dir).chain_err(|| format!("failed to create {}", dir.display()))?;
+            sysroot.output_dir = Some(dir);
+        }
+        if self.kind == TestKind::Manual {
+            let outcome = self.ask(commit, triple, &sysroot)?;
+            info!("tested {:} for {} from {}: {:?}", &commit.sha[0..9], triple, commit.date.to_rfc2822(), outcome);
+            return Ok((outcome, None, Duration::default()));
+        }
+        let log = if self.capture {
+            Some(self.create_log(commit, triple)?)
+        } else {
+            None
+        };
+
+        let mut outcomes = Vec::new();
+        let mut measurements = Vec::new();
+        let mut tested = Duration::default();
+        while outcomes.len() < self.runs {
+            let run_start = Instant::now();
+            let (outcome, measurement) = self.run_once(&sysroot, log.as_ref())?;
+            tested += run_start.elapsed();
+            if outcome == TestOutcome::Missing {
+                // The test can't tell for this commit, so running it again
+                // won't help.
+                info!("skipping {}, as the test asked to", &commit.sha[0..9]);
+                return Ok((outcome, None, tested));
+            }
+            if self.runs > 1 {
+                info!("run {} of {} for {}: {:?}", outcomes.len() + 1, self.runs, &commit.sha[0..9], outcome);
+            }
+            outcomes.push(outcome);
+            measurements.extend(measurement);
+            if self.quorum.is_decided(&self.broken_runs(&outcomes), self.runs) {
+                break;
+            }
+        }
+        let broken = self.broken_runs(&outcomes);
+        if self.runs > 1 {
+            info!("{} of {} runs of {} were broken", broken.iter().filter(|&&b| b).count(), outcomes.len(),
+                &commit.sha[0..9]);
+        }
+        // Report the outcome of a run that agrees with the quorum.
+        let is_broken = self.quorum.is_broken(&broken, self.runs);
+        let outcome = *outcomes.iter().rev().find(|&&outcome| self.is_broken(outcome) == is_broken).unwrap();
+        info!("tested {:} for {} from {}: {:?}", &commit.sha[0..9], triple, commit.date.to_rfc2822(), outcome);
+        // The median, which a single noisy run doesn't throw off.
+        measurements.sort_by(|a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal));
+        let measurement = measurements.get(measurements.len() / 2).cloned();
+        if let (Some(threshold), Some(measurement)) = (self.perf_threshold, measurement) {
+            info!("measured {} for {}: {} (threshold {})", &commit.sha[0..9], triple, measurement, threshold);
+        }
+        Ok((outcome, measurement, tested))
+    }
+
+    // Shows the user where to find the toolchain of `commit` and asks them
+    // whether it's good or bad, or should be skipped like a commit without
+    // artifacts.
+    fn ask(&self, commit: &Commit, triple: &str, sysroot: &Sysroot) -> Result<TestOutcome> {
+        eprintln!();
+        eprintln!("{} for {} from {}: {}", commit.sha, triple, commit.date.to_rfc2822(), commit.summary);
+        eprintln!("    RUSTC={}", sysroot.rustc.display());
+        eprintln!("    RUSTDOC={}", sysroot.rustdoc.display());
+        if let Some(ref cargo) = sysroot.cargo {
+            eprintln!("    CARGO={}", cargo.display());
+        }
+        if let Some(ref output_dir) = sysroot.output_dir {
+            eprintln!("    BISECT_OUTPUT_DIR={}", output_dir.display());
+        }
+        let stdin = io::stdin();
+        loop {
+            eprint!("Is it good, bad or skip? [g/b/s] ");
+            let mut answer = String::new();
+            if stdin.lock().read_line(&mut answer)? == 0 {
+                bail!("stdin was closed while waiting for an answer about {}", commit.sha);
+            }
+            match answer.trim() {
+                "g" | "good" => return Ok(TestOutcome::Passed),
+                "b" | "bad" => return Ok(TestOutcome::BuildFailed),
+                "s" | "skip" => return Ok(TestOutcome::Missing),
+                _ => {}
+            }
+        }
+    }
+
+    // Runs the test once. If it passed, the measurement of its last stage is
+    // returned too, and with `--perf` it's only `Passed` up to the threshold.
+    // A script without `--stages` is a single stage of its own.
+    fn run_once(&self, sysroot: &Sysroot, log: Option<&File>) -> Result<(TestOutcome, Option<f64>)> {
+        let first_stage = if self.kind == TestKind::Script { "test" } else { "build" };
+        let (outcome, measurement) = match self.run_stage(sysroot, first_stage, log)? {
+            (StageResult::TimedOut, _) => (TestOutcome::TimedOut, None),
+            (StageResult::Skipped, _) => (TestOutcome::Missing, None),
+            (StageResult::Failed, _) if self.kind == TestKind::Script => (TestOutcome::Failed, None),
+            (StageResult::Failed, _) => (TestOutcome::BuildFailed, None),
+            (StageResult::Passed, measurement) if !self.kind.has_run_stage() =>
+                (TestOutcome::Passed, Some(measurement)),
+            (StageResult::Passed, _) => match self.run_stage(sysroot, "run", log)? {
+                (StageResult::TimedOut, _) => (TestOutcome::TimedOut, None),
+                (StageResult::Skipped, _) => (TestOutcome::Missing, None),
+                (StageResult::Failed, _) => (TestOutcome::RunFailed, None),
+                (StageResult::Passed, measurement) => (TestOutcome::Passed, Some(measurement)),
+            },
+        };
+        match (self.perf_threshold, measurement) {
+            (Some(threshold), Some(measurement)) if measurement > threshold => Ok((TestOutcome::Slow, Some(measurement))),
+            _ => Ok((outcome, measurement)),
+        }
+    }
+
+    fn broken_runs(&self, outcomes: &[TestOutcome]) -> Vec<bool> {
+        outcomes.iter().map(|&outcome| self.is_broken(outcome)).collect()
+    }
+
+    fn is_broken(&self, outcome: TestOutcome) -> bool {
+        match outcome {
+            TestOutcome::TimedOut => self.timeout_is_regression,
+            outcome => self.regression_kind.is_broken(outcome),
+        }
+    }
+
+    /// Whether the commit with this outcome comes after the one looked for,
+    /// i.e. the predicate the bisection searches with, or `None` if the
+    /// commit couldn't be tested.
+    fn is_found(&self, outcome: TestOutcome) -> Option<bool> {
+        match (self.find, outcome) {
+            (_, TestOutcome::Missing) => None,
+            (Find::Regression, outcome) => Some(self.is_broken(outcome)),
+            (Find::Fix, outcome) => Some(!self.is_broken(outcome)),
+        }
+    }
+}
+
+/// How many lines of the captured output to print for the regressing commit.
+const LOG_TAIL_LINES: usize = 20;
+
+fn print_log_tail(path: &Path) -> Result<()> {
+    let mut log = Vec::new();
+    File::open(path)?.read_to_end(&mut log)?;
+    let log = String::from_utf8_lossy(&log);
+    let lines = log.lines().collect::<Vec<_>>();
+    println!("last lines of the test output in {}:", path.display());
+    for line in &lines[lines.len().saturating_sub(LOG_TAIL_LINES)..] {
+        println!("    {}", line);
+    }
+    Ok(())
+}
+
+/// An in-progress bisection, saved after every tested commit so that an
+/// interrupted run can resume without testing those commits again.
+#[derive(Serialize, Deserialize)]
+struct Session {
+    test_case: PathBuf,
+    #[serde(default)]
+    test_args: Vec<String>,
+    start: String,
+    end: String,
+    #[serde(default)]
+    triples: Vec<String>,
+    /// The outcome of each tested commit, keyed by SHA.
+    outcomes: HashMap<String, TestOutcome>,
+    /// With `--perf`, the measurement of each tested commit, keyed by SHA.
+    #[serde(default)]
+    measurements: HashMap<String, f64>,
+}
+
+impl Session {
+    // Loads the session saved at `path` if it's for the same bisection, or
+    // starts a new one otherwise.
+    fn load(path: &Path, test_case: &Path, test_args: &[String], start: &str, end: &str, triples: &[String])
+            -> Result<Session> {
+        let new = Session {
+            test_case: test_case.to_owned(),
+            test_args: test_args.to_vec(),
+            start: start.to_string(),
+            end: end.to_string(),
+            triples: triples.to_vec(),
+            outcomes: HashMap::new(),
+            measurements: HashMap::new(),
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(new),
+            Err(err) => return Err(err.into()),
+        };
+        let session: Session = serde_json::from_reader(file)
+            .chain_err(|| format!("failed to parse session {}; use --reset to discard it", path.display()))?;
+        if session.test_case != new.test_case || session.test_args != new.test_args
+            || session.start != new.start || session.end != new.end
+            || session.triples != new.triples {
+            warn!("discarding session {} of a different bisection", path.display());
+            return Ok(new);
+        }
+        info!("resuming session with {} tested commits", session.outcomes.len());
+        Ok(session)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        serde_json::to_writer_pretty(File::create(path)?, self)
+            .chain_err(|| format!("failed to write session {}", path.display()))
+    }
+
+    // Returns the recorded outcome for `commit`, or tests it and records the
+    // outcome in the session saved at `path`.
+    fn outcome(&mut self, path: &Path, tester: &Tester, commit: &Commit) -> Result<TestOutcome> {
+        if let Some(&outcome) = self.outcomes.get(&commit.sha) {
+            info!("using recorded result for {}: {:?}", &commit.sha[0..9], outcome);
+            return Ok(outcome);
+        }
+        let (outcome, measurement) = tester.test(commit)?;
+        self.outcomes.insert(commit.sha.clone(), outcome);
+        if let Some(measurement) = measurement {
+            self.measurements.insert(commit.sha.clone(), measurement);
+        }
+        self.save(path)?;
+        Ok(outcome)
+    }
+}
+
+/// The outcome of a bisection, as printed by `--output json`.
+#[derive(Serialize)]
+struct Report<'a> {
+    /// Whether `found` is a regression or a fix.
+    kind: Find,
+    /// The triples the commits were tested for.
+    triples: &'a [String],
+    /// The first broken commit (or first fixed one, with `--find fix`), if
+    /// it is within the range.
+    found: Option<&'a Commit>,
+    /// Whether `found` is within the range, or all of the commits work or
+    /// are already broken.
+    transition: Transition,
+    start: &'a Commit,
+    end: &'a Commit,
+    steps: usize,
+    /// Whether the test gave the same result across the whole range, so that
+    /// no transition from working to broken was observed.
+    inconclusive: bool,
+    /// Commits near `found` whose result contradicted the bisection when
+    /// re-tested by `--validate`.
+    contradictory: Vec<&'a Commit>,
+    /// The PR `found` merged, if it could be found out.
+    pr: Option<PullRequest>,
+    /// Every commit whose result differs from the commit before it, found by
+    /// testing the whole range with `--keep-going`.
+    transitions: Vec<&'a Commit>,
+    /// Commits that had no artifacts and were skipped with `--skip-missing`.
+    skipped: Vec<&'a Commit>,
+    /// The modules downloaded from a fallback URL during this run, which may
+    /// not be the artifacts expected.
+    fallback_downloads: Vec<DownloadedFrom>,
+    /// With `--perf`, the measurement of every tested commit, in the order of
+    /// the range.
+    measurements: Vec<Measurement<'a>>,
+}
+
+/// What `--perf` measured for a commit.
+#[derive(Serialize)]
+struct Measurement<'a> {
+    commit: &'a Commit,
+    value: f64,
+    /// Whether it exceeded the threshold, i.e. the commit counted as slow.
+    slow: bool,
+}
+
+/// How many commits on each side of the regression `--validate` re-tests.
+const VALIDATION_RADIUS: usize = 2;
+
+// Re-tests `found` and the commits on either side of it, returning those which
+// contradict the bisection: commits before `found` for which `is_found` holds,
+// or ones from it on for which it doesn't. With a monotonic test this is
+// always empty.
+fn validate<P>(commits: &[Commit], found: usize, mut is_found: P) -> Result<Vec<&Commit>>
+    where P: FnMut(&Commit) -> Result<Option<bool>>
+{
+    let lo = found.saturating_sub(VALIDATION_RADIUS);
+    let hi = cmp::min(found + VALIDATION_RADIUS + 1, commits.len());
+    let mut contradictory = Vec::new();
+    for (i, commit) in (lo..).zip(&commits[lo..hi]) {
+        if let Some(holds) = is_found(commit)? {
+            if holds != (i >= found) {
+                contradictory.push(commit);
+            }
+        }
+    }
+    Ok(contradictory)
+}
+
+/// The outcome of testing a single try build, as printed by `--output json`.
+#[derive(Serialize)]
+struct TryBuildReport<'a> {
+    commit: &'a Commit,
+    outcome: TestOutcome,
+    broken: bool,
+}
+
+// Resolves one end of the search range, preferring `pr_arg` if it was given.
+fn boundary(matches: &ArgMatches, client: &Client, sha_arg: &str, pr_arg: &str) -> Result<String> {
+    if matches.is_present(pr_arg) {
+        let pr = value_t!(matches, pr_arg, u32).unwrap_or_else(|e| e.exit());
+        Ok(github::commit_for_pr(client, pr)?.sha)
+    } else {
+        Ok(matches.value_of(sha_arg).unwrap().to_string())
+    }
+}
+
+// Resolves `rev` to a SHA with `resolve_rev`, telling how to search the
+// same range again if it wasn't one already.
+fn pin(rev: &str, config: &sysroot::Config, print: bool) -> Result<String> {
+    let sha = rust_sysroot::resolve_rev(rev, config)?;
+    if sha != rev && print {
+        println!("{} is {}; pass that instead to search the same range again", rev, sha);
+    }
+    Ok(sha)
+}
+
+// Tests every commit in `commits`, returning those for which `is_found`
+// differs from the commit before them. With a monotonic test and a single
+// cause, that's only the commit the bisection found.
+fn scan<P>(commits: &[Commit], mut is_found: P) -> Result<Vec<&Commit>>
+    where P: FnMut(&Commit) -> Result<Option<bool>>
 {
-    let mut base = 0usize;
-    let mut s = slice;
+    let mut transitions = Vec::new();
+    let mut previous = None;
+    for commit in commits {
+        let holds = match is_found(commit)? {
+            Some(holds) => holds,
+            None => continue,
+        };
+        if previous.is_some_and(|previous| previous != holds) {
+            transitions.push(commit);
+        }
+        previous = Some(holds);
+    }
+    Ok(transitions)
+}
+
+// The index `least_satisfying` probes after `probe`, given the window
+// `[lo, hi)` it was probed in and whether it was broken.
+fn next_probe(lo: usize, hi: usize, probe: usize, broken: bool) -> Option<usize> {
+    let (lo, hi) = if broken { (lo, probe) } else { (probe + 1, hi) };
+    if lo < hi {
+        Some(lo + ((hi - lo) >> 1))
+    } else {
+        None
+    }
+}
+
+fn finish_prefetch(sha: &str, handle: JoinHandle<rust_sysroot::errors::Result<()>>) {
+    match handle.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => warn!("prefetching {} failed: {}", sha, err),
+        Err(_) => warn!("prefetching {} panicked", sha),
+    }
+}
+
+// Tells how many commits by authors other than the `merge_bots` were left
+// out of the range, listing them at trace level. They're printed in text
+// mode, and only logged otherwise so as not to get into the report.
+fn print_skipped_authors(merge_bots: &[String], skipped: Option<&[Commit]>, print: bool) {
+    let skipped = match skipped {
+        Some(skipped) => skipped,
+        None => {
+            info!("not known how many commits by others than {} were left out of the range",
+                merge_bots.join(", "));
+            return;
+        }
+    };
+    for commit in skipped {
+        trace!("left out {} from {}: {}", commit.sha, commit.date.to_rfc2822(), commit.summary);
+    }
+    if skipped.is_empty() {
+        return;
+    }
+    let message = format!("left out {} commits in the range not merged by {}; \
+                           set RUST_LOG=bisect=trace to list them", skipped.len(), merge_bots.join(" or "));
+    if print {
+        println!("{}", message);
+    } else {
+        info!("{}", message);
+    }
+}
+
+// The number of probes it takes to narrow `remaining` commits down to none,
+// if none of them gets skipped.
+fn steps_for(remaining: usize) -> u32 {
+    0usize.leading_zeros() - remaining.leading_zeros()
+}
+
+// Prints how far along the bisection is to stderr, so that it doesn't end up
+// in the JSON report.
+fn print_progress(steps: usize, remaining: usize, tested: u32, testing_time: Duration) {
+    let steps_left = steps_for(remaining);
+    if tested == 0 || steps_left == 0 {
+        eprintln!("step {} done, {} steps left", steps, steps_left);
+        return;
+    }
+    let per_step = testing_time / tested;
+    eprintln!("step {} done, {} steps left, about {} remaining ({} per step)",
+        steps, steps_left, format_duration(per_step * steps_left), format_duration(per_step));
+}
+
+// Prints how many archives came from the cache and how many were downloaded,
+// to stderr like the progress.
+fn print_download_stats(tester: &Tester) {
+    let stats = sysroot::download_stats();
+    eprintln!("{} archives used from the cache, {} downloaded ({} MB)",
+        stats.cache_hits, stats.downloads, stats.downloaded_bytes / 1_000_000);
+    if tester.verbose_timing {
+        eprintln!("in total {:.1}s was spent downloading, {:.1}s extracting and {:.1}s testing",
+            secs(stats.download_time), secs(stats.extract_time), secs(tester.test_time.get()));
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+// Prints the result as a block of markdown to paste into an issue, which only
+// links to the commits rather than listing everything the text output does.
+fn print_triage(report: &Report) {
+    let (noun, label, merged_label) = match report.kind {
+        Find::Regression => ("regression", "regressed commit", "regressing PR"),
+        Find::Fix => ("fix", "fixed commit", "fixing PR"),
+    };
+    println!("searched commits: from {} to {}", github::commit_url(&report.start.sha),
+        github::commit_url(&report.end.sha));
+    println!("searched triples: {}", report.triples.join(", "));
+    match report.transition {
+        Transition::Found(_) => {
+            let commit = report.found.unwrap();
+            println!("{}: {} ({})", label, github::commit_url(&commit.sha), commit.date.format("%Y-%m-%d"));
+            if let Some(ref pr) = report.pr {
+                println!("{}: {} \"{}\" by @{}", merged_label, github::pr_url(pr.number), pr.title, pr.author);
+            }
+        }
+        Transition::AllPass => println!("no {} in range: none of the commits is {}",
+            noun, report.kind.state()),
+        Transition::AllFail => println!("no {} in range: the first commit is already {}",
+            noun, report.kind.state()),
+    }
+    if !report.contradictory.is_empty() {
+        println!("note: re-testing gave contradictory results, so the test may be flaky");
+    }
+}
+
+// Looks up the PR that `commit` merged, only warning if that fails since the
+// bisection itself succeeded.
+fn explain(client: &Client, commit: &Commit) -> Option<PullRequest> {
+    match github::pr_for_commit(client, commit) {
+        Ok(pr) => pr,
+        Err(err) => {
+            warn!("could not look up the PR merged by {}: {}", commit.sha, err);
+            None
+        }
+    }
+}
+
+// Resolves the path given to `--rustdoc`, which is either a file to document
+// or the directory of a crate.
+fn rustdoc_target(path: &Path) -> Result<PathBuf> {
+    let target = path.canonicalize()
+        .chain_err(|| format!("{} does not exist", path.display()))?;
+    if target.is_dir() && !target.join("Cargo.toml").is_file() {
+        bail!("{} is a directory without a Cargo.toml", path.display());
+    }
+    Ok(target)
+}
+
+// Resolves the path of the test script, checking that it can be run before
+// anything gets downloaded.
+fn test_case(path: &Path) -> Result<PathBuf> {
+    let test_case = path.canonicalize()
+        .chain_err(|| format!("test script {} does not exist", path.display()))?;
+    let metadata = fs::metadata(&test_case)?;
+    if !metadata.is_file() {
+        bail!("test script {} is not a file", path.display());
+    }
+    #[cfg(unix)]
+    {
+        if metadata.permissions().mode() & 0o111 == 0 {
+            bail!("test script {} is not executable; try chmod +x", path.display());
+        }
+    }
+    Ok(test_case)
+}
+
+/// The `--triple` which stands for the one the test declares.
+const AUTO_TRIPLE: &str = "auto-from-test";
+
+// Infers the triple a cross-compiling test is for, from the `triple_from`
+// file if it's given and otherwise from the test itself, so that the
+// rust-std for its target is downloaded rather than the host's. Falls back to
+// the host's triple if neither declares one.
+fn infer_triple(matches: &ArgMatches, triple_from: Option<&Path>) -> Result<String> {
+    let declared = match triple_from {
+        Some(path) => {
+            let contents = fs::read_to_string(path)
+                .chain_err(|| format!("failed to read {}", path.display()))?;
+            declared_triple(&contents, true).map(|triple| (triple, path))
+        }
+        None => ["test", "src", "rustdoc"].iter()
+            .filter_map(|arg| matches.value_of_os(arg).map(Path::new))
+            .find(|path| path.is_file())
+            .and_then(|path| {
+                let contents = fs::read_to_string(path).ok()?;
+                declared_triple(&contents, false).map(|triple| (triple, path))
+            }),
+    };
+    match declared {
+        Some((triple, path)) => {
+            info!("using triple {}, as declared by {}", triple, path.display());
+            Ok(triple)
+        }
+        None => {
+            let host = get_host_triple()?;
+            warn!("the test declares no triple (with BISECT_TRIPLE=<triple> or --triple-from), using the host's, {}",
+                host);
+            Ok(host)
+        }
+    }
+}
+
+// Finds the triple declared in `contents`, by a `BISECT_TRIPLE=<triple>` line,
+// be it an assignment or in a comment. A `manifest` may instead be a Cargo
+// config setting `target = "<triple>"`, or hold nothing but the triple.
+fn declared_triple(contents: &str, manifest: bool) -> Option<String> {
+    let is_triple = |word: &str| word.contains('-')
+        && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    let words = contents.split_whitespace().collect::<Vec<_>>();
+    if manifest && words.len() == 1 && is_triple(words[0]) {
+        return Some(words[0].to_string());
+    }
+    for line in contents.lines() {
+        let value = if let Some(start) = line.find("BISECT_TRIPLE=") {
+            &line[start + "BISECT_TRIPLE=".len()..]
+        } else if manifest && line.trim_start().starts_with("target") {
+            let rest = line.trim_start()["target".len()..].trim_start();
+            if !rest.starts_with('=') {
+                continue;
+            }
+            &rest[1..]
+        } else {
+            continue;
+        };
+        let value = value.split_whitespace().next().unwrap_or("")
+            .trim_matches(|c| c == '"' || c == '\'' || c == ';');
+        if is_triple(value) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+// Writes the `git bisect` commands which replay a search of `commits` with
+// these `probes` in a clone of rust-lang/rust.
+fn git_bisect_log(tester: &Tester, commits: &[Commit], probes: &[(String, Option<bool>)]) -> String {
+    let (old, new) = match tester.find {
+        Find::Regression => ("good", "bad"),
+        Find::Fix => ("broken", "fixed"),
+    };
+    let mut log = format!("# testing {} for {}\n", tester.test_case.display(), tester.triples.join(", "));
+    log.push_str("git bisect start --first-parent");
+    if tester.find == Find::Fix {
+        log.push_str(&format!(" --term-old={} --term-new={}", old, new));
+    }
+    log.push_str(&format!(" {} {}\n", commits.last().unwrap().sha, commits.first().unwrap().sha));
+    for &(ref sha, holds) in probes {
+        let term = match holds {
+            Some(true) => new,
+            Some(false) => old,
+            None => "skip",
+        };
+        log.push_str(&format!("git bisect {} {}\n", term, sha));
+    }
+    log
+}
+
+// Removes the commits listed in the file at `path` from `commits`. The file
+// has a SHA per line, which may be abbreviated; blank lines and lines starting
+// with `#` are ignored.
+fn skip_commits(commits: Vec<Commit>, path: &Path) -> Result<Vec<Commit>> {
+    let list = fs::read_to_string(path)
+        .chain_err(|| format!("failed to read the commits to skip from {}", path.display()))?;
+    let skip = list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>();
+    for sha in &skip {
+        if !commits.iter().any(|c| c.sha.starts_with(sha)) {
+            warn!("{} from {} is not in the range", sha, path.display());
+        }
+    }
+    let len = commits.len();
+    let commits = commits.into_iter()
+        .filter(|c| !skip.iter().any(|sha| c.sha.starts_with(sha)))
+        .collect::<Vec<_>>();
+    info!("skipping {} commits listed in {}", len - commits.len(), path.display());
+    if commits.is_empty() {
+        bail!("every commit in the range is listed in {}", path.display());
+    }
+    Ok(commits)
+}
+
+// Reads the commits to search from `path`, one SHA per line, and resolves
+// them. Commits without artifacts are only warned about, as `--skip-missing`
+// may be able to deal with them.
+fn listed_commits(tester: &Tester, path: &Path) -> Result<Vec<Commit>> {
+    let list = fs::read_to_string(path)
+        .chain_err(|| format!("failed to read the commits to search from {}", path.display()))?;
+    let shas = list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>();
+    if shas.is_empty() {
+        bail!("{} lists no commits", path.display());
+    }
+    let commits = rust_sysroot::resolve_commits(&shas, &tester.config)
+        .chain_err(|| format!("failed to resolve the commits listed in {}", path.display()))?;
+    info!("searching the {} commits listed in {}", commits.len(), path.display());
+    for commit in &commits {
+        for triple in &tester.triples {
+            match Sysroot::has_artifacts(commit, triple, &tester.config) {
+                Ok(true) => {}
+                Ok(false) => warn!("{} from {} has no artifacts for {}", commit.sha, path.display(), triple),
+                Err(err) => warn!("failed to check for artifacts of {} for {}: {}", commit.sha, triple, err),
+            }
+        }
+    }
+    Ok(commits)
+}
 
+fn parse_date(date: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .chain_err(|| format!("invalid date {}, expected YYYY-MM-DD", date))
+}
+
+/// How many days apart the commits rustc and std are installed from may be
+/// before `--std-sha` warns that they're unlikely to work together.
+const MAX_STD_DISTANCE_DAYS: i64 = 7;
+
+// Warns if the `--std-sha` commit is far from some of the `commits` it's
+// combined with, since a std that changed ABI or metadata format since (or
+// until) the rustc it's used with is rejected or miscompiled.
+fn warn_std_distance(client: &Client, std_sha: &str, commits: &[Commit]) {
+    let std_date = match commits.iter().find(|c| c.sha.starts_with(std_sha)) {
+        Some(commit) => commit.date,
+        None => match github::get_commit(client, std_sha) {
+            Ok(commit) => commit.date,
+            Err(err) => {
+                warn!("failed to look up the date of {}, to check it's close to the range: {}", std_sha, err);
+                return;
+            }
+        },
+    };
+    let distance = commits.iter()
+        .map(|commit| (commit.date - std_date).num_days().abs())
+        .max()
+        .unwrap_or(0);
+    if distance > MAX_STD_DISTANCE_DAYS {
+        warn!("rust-std from {} is up to {} days apart from the commits tested with it; \
+               rustc is likely to reject it or be incompatible with it", std_sha, distance);
+    }
+}
+
+// Parses the argument to `--since`, either a date or a number of days or
+// weeks before now.
+fn parse_since(since: &str) -> Result<DateTime<Utc>> {
+    let days = if since.ends_with('d') {
+        Some(1)
+    } else if since.ends_with('w') {
+        Some(7)
+    } else {
+        None
+    };
+    match days {
+        Some(days) => match since[..since.len() - 1].parse::<i64>() {
+            Ok(n) => Ok(Utc::now() - chrono::Duration::days(n * days)),
+            Err(_) => bail!("invalid duration {}, expected e.g. 14d or 2w", since),
+        },
+        None => Ok(DateTime::from_utc(parse_date(since)?.and_hms(0, 0, 0), Utc)),
+    }
+}
+
+// Narrows the search down to the commits between two consecutive nightlies,
+// by first bisecting over the nightlies published from `start` to `end`.
+// Returns the commits the nightlies before and after the regression (or fix)
+// were built from.
+fn bisect_nightlies(tester: &Tester, client: &Client, start: NaiveDate, end: NaiveDate) -> Result<(String, String)> {
+    let mut dates = Vec::new();
+    let mut date = start;
+    while date <= end {
+        dates.push(date);
+        date += chrono::Duration::days(1);
+    }
+
+    let nightly = |date: &NaiveDate| -> Result<Commit> {
+        let (date, sha) = nightly::nightly_on_or_before(client, *date)?;
+        Ok(Commit {
+            sha,
+            date: DateTime::from_utc(date.and_hms(0, 0, 0), Utc),
+            summary: format!("nightly-{}", date),
+        })
+    };
+
+    info!("searching in the nightlies from {} to {}", start, end);
+    let (found, _) = try_least_satisfying_skipping(&dates, |date, _| -> Result<Option<bool>> {
+        let commit = nightly(date)?;
+        Ok(tester.is_found(tester.test(&commit)?.0))
+    })?;
+    match Transition::new(found, dates.len()) {
+        Transition::Found(_) => {}
+        Transition::AllFail => bail!("the nightly of {} is already {}", start, tester.find.state()),
+        Transition::AllPass => bail!("the nightly of {} is not {} yet", end, tester.find.state()),
+    }
+
+    let (before, after) = (nightly(&dates[found - 1])?, nightly(&dates[found])?);
+    info!("{:?} between {} and {}", tester.find, before.summary, after.summary);
+    Ok((before.sha, after.sha))
+}
+
+/// How many times `--expand-range` widens the range before giving up.
+const MAX_EXPANSIONS: u32 = 6;
+
+// How far `--expand-range` moves an end of the range from `first...last` on
+// its `expansion`th widening: as far as the range spans, but at least a day,
+// doubled each time after the first.
+fn widening_span(first: &Commit, last: &Commit, expansion: u32) -> chrono::Duration {
+    cmp::max(last.date - first.date, chrono::Duration::days(1)) * 2i32.pow(expansion - 1)
+}
+
+// Tests the first and last of `commits`, and as long as the first one is
+// already broken or the last one still isn't, widens the range on that side
+// by going back or forward as long as it spans, to the nightly on or before
+// then, doubling each time. Returns the commits of the widened range, or an
+// error if even `MAX_EXPANSIONS` didn't find one that brackets the change.
+fn expand_range(tester: &Tester, client: &Client, mut commits: Vec<Commit>, print: bool) -> Result<Vec<Commit>> {
+    let state = tester.find.state();
+    let first_commit = commits[0].clone();
+    let mut first = tester.is_found(tester.test(&first_commit)?.0);
+    let last_commit = commits[commits.len() - 1].clone();
+    let mut last = tester.is_found(tester.test(&last_commit)?.0);
+    let mut expansions = 0;
     loop {
-        let (head, tail) = s.split_at(s.len() >> 1);
-        if tail.is_empty() {
-            return base + head.len();
+        let (start, end) = (commits[0].clone(), commits[commits.len() - 1].clone());
+        let (widen_start, widen_end) = match (first, last) {
+            (Some(true), Some(false)) => bail!("the first commit {} is already {}, but the last one {} isn't; \
+                                                the test isn't monotonic over this range", start.sha, state, end.sha),
+            (first, last) => (first == Some(true), last == Some(false)),
+        };
+        if !widen_start && !widen_end {
+            return Ok(commits);
+        }
+        let why = if widen_start {
+            format!("the first commit {} is already {}", start.sha, state)
+        } else {
+            format!("the last commit {} is not {}", end.sha, state)
+        };
+        if expansions == MAX_EXPANSIONS {
+            bail!("no change found even after widening the range {} times: {}", MAX_EXPANSIONS, why);
+        }
+        expansions += 1;
+        let span = widening_span(&first_commit, &last_commit, expansions);
+
+        let new_start = if widen_start {
+            nightly::nightly_on_or_before(client, (start.date - span).naive_utc().date())?.1
+        } else {
+            start.sha.clone()
+        };
+        let new_end = if widen_end {
+            let date = (end.date + span).naive_utc().date();
+            if date < Utc::today().naive_utc() {
+                nightly::nightly_on_or_before(client, date)?.1
+            } else {
+                let master = rust_sysroot::resolve_rev("master", &tester.config)?;
+                if master == end.sha {
+                    bail!("no change found: even master {} is not {}", master, state);
+                }
+                master
+            }
+        } else {
+            end.sha.clone()
+        };
+        commits = rust_sysroot::get_commits(&new_start, &new_end, &tester.config)?;
+        if print {
+            println!("{}, widening the range to {}...{} ({} commits)", why, new_start, new_end, commits.len());
+        }
+        if widen_start {
+            first = tester.is_found(tester.test(&commits[0])?.0);
+        }
+        if widen_end {
+            last = tester.is_found(tester.test(&commits[commits.len() - 1])?.0);
+        }
+    }
+}
+
+// Checks every one of `commits` for the artifacts of each module and triple,
+// `--jobs` commits at a time, and prints those which are missing.
+fn list_missing_artifacts(tester: &Tester, commits: &[Commit]) -> Result<()> {
+    let mut incomplete = 0;
+    for chunk in commits.chunks(cmp::max(tester.config.jobs, 1)) {
+        let handles = chunk.iter().map(|commit| {
+            let (commit, triples, config) = (commit.clone(), tester.triples.clone(), tester.config.clone());
+            thread::spawn(move || {
+                triples.into_iter().map(|triple| {
+                    let missing = Sysroot::missing_modules(&commit, &triple, &config);
+                    (triple, missing)
+                }).collect::<Vec<_>>()
+            })
+        }).collect::<Vec<_>>();
+        for (commit, handle) in chunk.iter().zip(handles) {
+            let results = match handle.join() {
+                Ok(results) => results,
+                Err(_) => bail!("checking {} for artifacts panicked", commit.sha),
+            };
+            let mut complete = true;
+            for (triple, missing) in results {
+                match missing {
+                    Ok(modules) => for module in modules {
+                        println!("{} {} {}", commit.sha, triple, module);
+                        complete = false;
+                    },
+                    Err(err) => warn!("failed to check for artifacts of {} for {}: {}", commit.sha, triple, err),
+                }
+            }
+            if !complete {
+                incomplete += 1;
+            }
         }
-        if predicate(&tail[0]) {
-            s = head;
+    }
+    println!("{} of {} commits are missing artifacts", incomplete, commits.len());
+    if incomplete > 0 {
+        println!("pass --skip-missing to skip them while bisecting");
+    }
+    Ok(())
+}
+
+/// How many commits `check_range_artifacts` moves the end of the range back
+/// by at most, looking for one with artifacts.
+const MAX_END_NUDGES: usize = 10;
+
+// Checks that the first and last of `commits` have artifacts for every
+// triple before anything is tested, as those of the latest master may still
+// be uploading. If the last one's are missing, the range is cut short at the
+// latest commit which has them. A first commit without them is an error,
+// unless `--skip-missing` is given.
+fn check_range_artifacts(tester: &Tester, mut commits: Vec<Commit>, print: bool) -> Result<Vec<Commit>> {
+    let has_artifacts = |commit: &Commit| tester.triples.iter().all(|triple| {
+        match Sysroot::has_artifacts(commit, triple, &tester.config) {
+            Ok(found) => found,
+            Err(err) => {
+                // It may well be there, so the search can go on and find out.
+                warn!("failed to check for artifacts of {} for {}: {}", commit.sha, triple, err);
+                true
+            }
+        }
+    });
+    let end = commits[commits.len() - 1].sha.clone();
+    let missing = commits.iter().rev().take(MAX_END_NUDGES + 1).take_while(|commit| !has_artifacts(commit)).count();
+    if missing == commits.len() {
+        bail!("none of the commits up to {} have artifacts for {}", end, tester.triples.join(", "));
+    }
+    if missing > MAX_END_NUDGES {
+        bail!("the last {} commits up to {} have no artifacts for {}; CI may still be uploading them, \
+               or they may have been removed", missing, end, tester.triples.join(", "));
+    }
+    if missing > 0 {
+        let len = commits.len() - missing;
+        commits.truncate(len);
+        let message = format!("{} has no artifacts (yet), so the range ends at {} instead, \
+                               leaving out the last {} commits", end, commits[len - 1].sha, missing);
+        if print {
+            println!("{}", message);
         } else {
-            base += head.len() + 1;
-            s = &tail[1..];
+            warn!("{}", message);
         }
     }
+    let start = &commits[0];
+    if commits.len() > 1 && !has_artifacts(start) {
+        if tester.skip_missing {
+            warn!("the first commit {} has no artifacts; it will be skipped", start.sha);
+        } else {
+            bail!("the first commit {} has no artifacts for {}; pick a later start, \
+                   or pass --skip-missing", start.sha, tester.triples.join(", "));
+        }
+    }
+    Ok(commits)
 }
 
 fn run() -> Result<i32> {
     env_logger::init();
 
+    // The sysroots are normally removed when they're dropped, which doesn't
+    // happen when the process is killed by the signal.
+    ctrlc::set_handler(|| {
+        eprintln!("interrupted, removing the sysroots in use");
+        sysroot::remove_interrupted();
+        std::process::exit(130);
+    }).chain_err(|| "failed to set the Ctrl-C handler")?;
+
     let matches = clap_app!(bisect =>
        (version: "0.1")
        (author: "The Rust Infrastructure Team")
        (about: "Find PRs introducing regressions into Rust")
        (@arg preserve_sysroots: -p --preserve "Don't delete sysroots after running.")
-       (@arg test: +required +takes_value --test "File to run to test for regression")
-       (@arg triple: +takes_value --triple "triple to use for downloads")
+       (@arg test: required_unless_one(&["rustdoc", "manual", "src"]) +takes_value --test "File to run to test for regression")
+       (@arg test_arg: +takes_value +multiple number_of_values(1) allow_hyphen_values(true) --("test-arg") requires[test]
+        "Run --test with this argument (repeatably) instead of as a script, e.g. --test cargo --test-arg test")
+       (@arg stages: --stages requires[test] conflicts_with[test_arg]
+        "Run the --test script as `test.sh build`, then as `test.sh run`, to tell build and run failures apart")
+       (@arg rustdoc: +takes_value --rustdoc conflicts_with[test local_rustc]
+        "Test whether rustdoc succeeds on this file, or with cargo doc on the crate in this directory, instead of running a script")
+       (@arg src: +takes_value --src conflicts_with[test rustdoc manual]
+        "Compile this Rust file with rustc, instead of running a script, and compare the result to --expect")
+       (@arg expect: +takes_value --expect possible_values(&["compile-pass", "compile-fail", "run-pass"])
+        default_value("compile-pass") "What should happen to the --src file on a working commit")
+       (@arg manual: --manual conflicts_with[test rustdoc]
+        "Print the paths of each commit's toolchain and ask whether it's good or bad, instead of running a script")
+       (@arg regression_kind: +takes_value --("regression-kind") possible_value[build run any] default_value[any]
+        "Which failing stage of the test counts as a regression")
+       (@arg find: +takes_value --find possible_value[regression fix] default_value[regression]
+        "Whether to find the commit which broke the test, or the one which fixed it")
+       (@arg triple: +takes_value +multiple number_of_values(1) --triple
+        "Triple to use for downloads; if given several times, a commit only counts as broken if it is for all of them. \
+         auto-from-test infers it from the test's BISECT_TRIPLE declaration or the --triple-from file")
+       (@arg triple_from: +takes_value --("triple-from")
+        "File declaring the triple for --triple auto-from-test: a Cargo config setting build.target, or just the triple")
+       (@arg each_triple: --("each-triple") conflicts_with[start_date]
+        "Bisect separately for each --triple, rather than only counting commits broken for all of them")
+       (@arg jobs: +takes_value -j --jobs default_value("3") "Number of sysroot modules to download in parallel")
+       (@arg download_retries: +takes_value --("download-retries") default_value("3")
+        "Number of times to retry a download after a transient error")
+       (@arg cache_dir: +takes_value --("cache-dir") "Directory to store sysroots in [default: $BISECT_CACHE or cache]")
+       (@arg max_cache_size: +takes_value --("max-cache-size")
+        "Size in MB to keep the cache under by evicting the least recently used sysroots")
+       (@arg free_space_factor: +takes_value --("free-space-factor") default_value("4")
+        "Fail before extracting an archive unless this many times its size is free in the cache directory; 0 to not check")
+       (@arg mirror: +takes_value +multiple number_of_values(1) --mirror
+        "Mirror of the CI artifacts to try first; a base URL or a template using @SHA@, @MODULE@ and @TRIPLE@ [default: $RUSTC_ARTIFACT_MIRROR]")
+       (@arg channel: +takes_value --channel possible_value[nightly beta stable] default_value[nightly]
+        "Release channel of the commits to bisect; beta and stable need --start and --end, e.g. release tags")
+       (@arg cargo_sha: +takes_value --("cargo-sha")
+        "Commit to use cargo from for every sysroot, instead of the tested commit's own")
+       (@arg std_sha: +takes_value --("std-sha")
+        "Commit to use rust-std from for every sysroot, to tell whether a change is in the compiler or the library")
+       (@arg merge_bot: +takes_value +multiple number_of_values(1) --("merge-bot")
+        "Also accept commits by this author as merge commits on master, besides bors")
+       (@arg no_local_repo: --("no-local-repo")
+        "List commits through the GitHub API instead of a local clone of rust-lang/rust")
+       (@arg components: +takes_value --components default_value("rustc,rust-std,cargo")
+        "Comma-separated modules to install, e.g. rustc,rust-std for tests that never run cargo")
+       (@arg offline: --offline "Only use the archives saved in the cache directory, never download any")
+       (@arg sysroot_dir: +takes_value --("sysroot-dir")
+        "Directory of sysroots downloaded ahead of time, as <sha>/rustc and <sha>/cargo, to use when there")
        (@arg start: +takes_value default_value(EPOCH_COMMIT) --start "First commit to search from")
        (@arg end: +takes_value default_value[master] --end "Last commit to search until")
+       (@arg start_pr: +takes_value --("start-pr") "PR whose merge commit to search from, instead of --start")
+       (@arg end_pr: +takes_value --("end-pr") "PR whose merge commit to search until, instead of --end")
+       (@arg since: +takes_value --since conflicts_with[start_pr start_date]
+        "Search from the first merge on or after this date (YYYY-MM-DD) or this long ago (e.g. 14d, 2w), instead of --start")
+       (@arg commit_range_file: +takes_value --("commit-range-file") conflicts_with[start_pr end_pr since start_date try_build]
+        "File listing the commits to search, one SHA per line in chronological order, instead of those from --start to --end")
+       (@arg start_date: +takes_value --("start-date")
+        "Bisect over the nightlies from this date (YYYY-MM-DD) first, then over the commits between two of them")
+       (@arg end_date: +takes_value --("end-date") requires[start_date]
+        "Date of the last nightly to search until with --start-date [default: today]")
+       (@arg output: +takes_value --output possible_value[text json triage] default_value[text]
+        "Format of the result; triage is a summary with links to paste into an issue")
+       (@arg validate: --validate "Re-test commits around the regression to check that the test isn't flaky")
+       (@arg keep_going: --("keep-going")
+        "After bisecting, test every commit in the range and report each one that changed the result")
+       (@arg dry_run: --("dry-run") "List the commits that would be tested, without downloading anything")
+       (@arg list_missing_artifacts: --("list-missing-artifacts") conflicts_with[dry_run expand_range manual]
+        "List which modules of which commits in the range have no artifacts, without bisecting")
+       (@arg expand_range: --("expand-range") conflicts_with[commit_range_file since dry_run]
+        "Test the first and last commit first, and widen the range while they don't bracket the change")
+       (@arg reset: --reset "Discard the results of a previously interrupted bisection")
+       (@arg emit_git_bisect: +takes_value --("emit-git-bisect")
+        "File to write the git bisect commands which replay the search to")
+       (@arg any_order: --("any-order")
+        "Search the commits even if they aren't in chronological order, as long as the test only changes once along them")
+       (@arg skip_commits: +takes_value --("skip-commits")
+        "File listing commits to leave out of the search, one SHA or SHA prefix per line")
+       (@arg refresh_commits: --("refresh-commits")
+        "List the commits in the range again, rather than use the list cached by an earlier run")
+       (@arg local_rustc: +takes_value --("local-rustc") conflicts_with[prefetch]
+        "Script which builds or locates a rustc for the commit given as its argument and prints its path; only cargo is downloaded")
+       (@arg prefetch: --prefetch requires[preserve_sysroots] conflicts_with[offline]
+        "Download the sysroots of the next commits to test in the background")
+       (@arg retries: +takes_value --retries default_value("1")
+        "Number of times to run the test for each commit, to cope with a flaky test")
+       (@arg quorum: +takes_value --quorum possible_value[any all majority] default_value[majority]
+        "Whether a commit is broken if any, all or most of its --retries runs were")
+       (@arg timeout: +takes_value --timeout "Seconds after which a stage of the test is killed")
+       (@arg timeout_is_regression: --("timeout-is-regression") requires[timeout]
+        "Count a test that timed out as broken, rather than working")
+       (@arg perf: +takes_value --perf conflicts_with[manual]
+        "Count a commit as broken if the test's measurement exceeds this: the number it prints on a \
+         `metric: <n>` line, or else the seconds its last stage took")
+       (@arg explain: --explain
+        "Print the window of commits searched at each step, which one is tested and which part is kept")
+       (@arg progress: --progress "Print the progress of downloads, and the number of steps left and an estimate of the time they take after each step")
+       (@arg skip_missing: --("skip-missing")
+        "Skip commits which have no artifacts and test one next to them instead, rather than stopping")
+       (@arg verify_sysroot: --("verify-sysroot") "Check that each sysroot can compile and run an empty program before testing")
+       (@arg capture: --capture "Save the output of the test for each commit in the logs directory of the cache")
+       (@arg log_file: +takes_value --("log-file")
+        "Append a JSON line with the outcome and duration of each tested commit to this file")
+       (@arg verbose_timing: --("verbose-timing")
+        "Print how long downloading, extracting and testing took for each commit, and in total")
+       (@arg pass_env: +takes_value +multiple number_of_values(1) --("pass-env")
+        "Pass this environment variable on to the test, e.g. HOME or RUSTFLAGS")
+       (@arg env_overrides: +takes_value --("env-overrides")
+        "TOML file of extra environment variables and RUSTFLAGS for the commits of some date or commit ranges")
+       (@arg rustc_flags: +takes_value allow_hyphen_values(true) --("rustc-flags")
+        "Flags to compile with at every commit, e.g. \"-Copt-level=3 -Cdebuginfo=2\", exported as RUSTFLAGS")
+       (@arg output_dir: +takes_value --("output-dir")
+        "Give the test a directory of its own for each commit in this directory, as BISECT_OUTPUT_DIR")
+       (@arg try_build: +takes_value --("try-build") "SHA of a try build to test on its own, instead of bisecting")
     ).get_matches();
 
-    let preserve_sysroots = matches.is_present("preserve_sysroots");
-    let test_case = Path::new(matches.value_of_os("test").expect("--test")).canonicalize()?;
-    let triple = match matches.value_of("triple") {
-        Some(x) => x.to_string(),
-        None => get_host_triple()?,
+    let json_output = matches.value_of("output") == Some("json");
+    // Only the text output is mixed with notes on how the search goes.
+    let text_output = matches.value_of("output") == Some("text");
+    let mut config = sysroot::Config {
+        cache_dir: matches.value_of_os("cache_dir").map(PathBuf::from)
+            .unwrap_or_else(sysroot::default_cache_dir),
+        jobs: value_t!(matches, "jobs", usize).unwrap_or_else(|e| e.exit()),
+        retries: value_t!(matches, "download_retries", u32).unwrap_or_else(|e| e.exit()),
+        try_build: matches.is_present("try_build"),
+        max_cache_size: if matches.is_present("max_cache_size") {
+            Some(value_t!(matches, "max_cache_size", u64).unwrap_or_else(|e| e.exit()) * 1_000_000)
+        } else {
+            None
+        },
+        mirrors: matches.values_of("mirror").map(|mirrors| mirrors.map(String::from).collect())
+            .unwrap_or_else(sysroot::default_mirrors),
+        channel: value_t!(matches, "channel", sysroot::Channel).unwrap_or_else(|e| e.exit()),
+        cargo_sha: matches.value_of("cargo_sha").map(String::from),
+        std_sha: matches.value_of("std_sha").map(String::from),
+        merge_bots: git::merge_bots(matches.values_of("merge_bot").into_iter().flatten()),
+        local_repo: !matches.is_present("no_local_repo"),
+        offline: matches.is_present("offline"),
+        sysroot_dir: matches.value_of_os("sysroot_dir").map(PathBuf::from),
+        refresh_commits: matches.is_present("refresh_commits"),
+        pass_env: matches.values_of("pass_env").map(|vars| vars.map(String::from).collect()).unwrap_or_default(),
+        env_overrides: Vec::new(),
+        rustc_flags: matches.value_of("rustc_flags").map(|flags| flags.split_whitespace().map(String::from).collect())
+            .unwrap_or_default(),
+        free_space_factor: value_t!(matches, "free_space_factor", u64).unwrap_or_else(|e| e.exit()),
+        progress: matches.value_of("output") != Some("json")
+            && (matches.is_present("progress") || sysroot::stderr_is_tty()),
+        components: sysroot::parse_components(matches.value_of("components").unwrap())?,
+    };
+    if matches.is_present("verify_sysroot") && !config.components.iter().any(|c| c == "rust-std") {
+        bail!("--verify-sysroot compiles a program, so it needs the rust-std component");
+    }
+    if let Some(path) = matches.value_of_os("env_overrides") {
+        // Commits bounding the ranges are looked up like the others.
+        config.env_overrides = overrides::load(Path::new(path), &config)?;
+    }
+    let triple_from = matches.value_of_os("triple_from").map(Path::new);
+    let triples = match matches.values_of("triple") {
+        Some(triples) => {
+            let triples = triples.collect::<Vec<_>>();
+            if triple_from.is_some() && !triples.contains(&AUTO_TRIPLE) {
+                bail!("--triple-from only applies to --triple {}", AUTO_TRIPLE);
+            }
+            let mut resolved = Vec::new();
+            for triple in triples {
+                let triple = if triple == AUTO_TRIPLE {
+                    infer_triple(&matches, triple_from)?
+                } else {
+                    triple.to_string()
+                };
+                if !resolved.contains(&triple) {
+                    resolved.push(triple);
+                }
+            }
+            resolved
+        }
+        // Naming the file is enough to ask for the triple it declares.
+        None if triple_from.is_some() => vec![infer_triple(&matches, triple_from)?],
+        None => vec![get_host_triple()?],
+    };
+    let tester = Tester {
+        test_case: if let Some(path) = matches.value_of_os("rustdoc") {
+            rustdoc_target(Path::new(path))?
+        } else if let Some(path) = matches.value_of_os("src") {
+            let path = Path::new(path);
+            path.canonicalize().chain_err(|| format!("{} does not exist", path.display()))?
+        } else if matches.is_present("manual") {
+            // Only used to tell sessions apart.
+            PathBuf::from("manual")
+        } else {
+            let path = Path::new(matches.value_of_os("test").expect("--test"));
+            // A command may be a program in `PATH`, or stand for one of the
+            // sysroot's tools.
+            if matches.is_present("test_arg") && path.components().count() == 1 && !path.is_file() {
+                path.to_owned()
+            } else {
+                test_case(path)?
+            }
+        },
+        test_args: matches.values_of("test_arg").map(|args| args.map(String::from).collect()).unwrap_or_default(),
+        kind: if matches.is_present("rustdoc") {
+            TestKind::Rustdoc
+        } else if matches.is_present("src") {
+            TestKind::Source(value_t!(matches, "expect", Expect).unwrap_or_else(|e| e.exit()))
+        } else if matches.is_present("manual") {
+            TestKind::Manual
+        } else if matches.is_present("test_arg") {
+            TestKind::Command
+        } else if matches.is_present("stages") {
+            TestKind::StagedScript
+        } else {
+            TestKind::Script
+        },
+        triples,
+        preserve_sysroots: matches.is_present("preserve_sysroots"),
+        config,
+        regression_kind: value_t!(matches, "regression_kind", RegressionKind).unwrap_or_else(|e| e.exit()),
+        find: value_t!(matches, "find", Find).unwrap_or_else(|e| e.exit()),
+        timeout: if matches.is_present("timeout") {
+            Some(Duration::from_secs(value_t!(matches, "timeout", u64).unwrap_or_else(|e| e.exit())))
+        } else {
+            None
+        },
+        timeout_is_regression: matches.is_present("timeout_is_regression"),
+        capture: matches.is_present("capture"),
+        log_file: matches.value_of_os("log_file").map(PathBuf::from),
+        verbose_timing: matches.is_present("verbose_timing"),
+        test_time: Cell::default(),
+        output_dir: match matches.value_of_os("output_dir") {
+            // Absolute, so that it still works if the test changes directory.
+            Some(dir) => {
+                fs::create_dir_all(dir).chain_err(|| format!("failed to create {}", Path::new(dir).display()))?;
+                Some(Path::new(dir).canonicalize()?)
+            }
+            None => None,
+        },
+        local_rustc: match matches.value_of_os("local_rustc") {
+            Some(script) => Some(test_case(Path::new(script))?),
+            None => None,
+        },
+        perf_threshold: if matches.is_present("perf") {
+            Some(value_t!(matches, "perf", f64).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        },
+        verify_sysroot: matches.is_present("verify_sysroot"),
+        skip_missing: matches.is_present("skip_missing"),
+        runs: value_t!(matches, "retries", usize).unwrap_or_else(|e| e.exit()),
+        quorum: value_t!(matches, "quorum", Quorum).unwrap_or_else(|e| e.exit()),
+    };
+
+    if tester.runs == 0 {
+        bail!("--retries must be at least 1");
+    }
+    if tester.kind == TestKind::Script && tester.regression_kind != RegressionKind::Any {
+        bail!("the script is run once, so it has no build and run stages to tell apart; pass --stages");
+    }
+    if !tester.kind.has_run_stage() && tester.regression_kind == RegressionKind::Run {
+        bail!("the test only has a build stage, so --regression-kind run never finds anything");
+    }
+    if tester.kind == TestKind::Rustdoc && tester.test_case.is_dir()
+        && !tester.config.components.iter().any(|c| c == "cargo") {
+        bail!("--rustdoc with a crate directory runs cargo doc, so it needs the cargo component");
+    }
+    if tester.kind == TestKind::Command && tester.test_case == Path::new("cargo")
+        && !tester.config.components.iter().any(|c| c == "cargo") {
+        bail!("--test cargo runs the sysroot's cargo, so it needs the cargo component");
+    }
+    if let TestKind::Source(_) = tester.kind {
+        if !tester.config.components.iter().any(|c| c == "rust-std") {
+            bail!("--src compiles the file, so it needs the rust-std component");
+        }
+    }
+
+    if let Some(sha) = matches.value_of("try_build") {
+        let commit = Commit::try_build(sha);
+        let (outcome, _) = tester.test(&commit)?;
+        let broken = tester.is_broken(outcome);
+        if json_output {
+            let report = TryBuildReport { commit: &commit, outcome, broken };
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        } else {
+            println!("try build {}: {:?}; {}", commit.sha, outcome, if broken { "broken" } else { "not broken" });
+        }
+        return Ok(0);
+    }
+
+    if tester.config.channel != sysroot::Channel::Nightly {
+        if matches.is_present("start_date") {
+            bail!("--start-date searches nightlies, so it can't be used with --channel {}",
+                tester.config.channel);
+        }
+        for &(arg, pr_arg) in &[("start", "start_pr"), ("end", "end_pr")] {
+            if matches.occurrences_of(arg) == 0 && !matches.is_present(pr_arg) {
+                bail!("--channel {} needs --{}, e.g. a release tag like 1.30.0",
+                    tester.config.channel, arg);
+            }
+        }
+    }
+
+    if matches.is_present("commit_range_file") {
+        for arg in &["start", "end"] {
+            if matches.occurrences_of(arg) > 0 {
+                bail!("--commit-range-file can't be used with --{}", arg);
+            }
+        }
+    }
+    if matches.is_present("expand_range") && tester.config.channel != sysroot::Channel::Nightly {
+        bail!("--expand-range widens the range by nightlies, so it can't be used with --channel {}",
+            tester.config.channel);
+    }
+    if matches.is_present("since") && matches.occurrences_of("start") > 0 {
+        bail!("--since can't be used with --start");
+    }
+    let since = match matches.value_of("since") {
+        Some(since) => Some(parse_since(since)?),
+        None => None,
+    };
+
+    let client = Client::new();
+    // The commits by other authors than the merge bots, which were left out of
+    // the range.
+    let (mut start, mut end, mut commits, mut skipped_authors) = if let Some(path) = matches.value_of_os("commit_range_file") {
+        let commits = listed_commits(&tester, Path::new(path))?;
+        (commits[0].sha.clone(), commits[commits.len() - 1].sha.clone(), commits, None)
+    } else {
+        let (start, end) = match matches.value_of("start_date") {
+            Some(start_date) => {
+                let end_date = match matches.value_of("end_date") {
+                    Some(end_date) => parse_date(end_date)?,
+                    None => Utc::today().naive_utc(),
+                };
+                bisect_nightlies(&tester, &client, parse_date(start_date)?, end_date)?
+            }
+            None => (boundary(&matches, &client, "start", "start_pr")?,
+                     boundary(&matches, &client, "end", "end_pr")?),
+        };
+        // Pinned, so that the range and the session stay the same if the
+        // search is run again after e.g. `master` moved on.
+        let (start, end) = (pin(&start, &tester.config, text_output)?, pin(&end, &tester.config, text_output)?);
+        let (commits, skipped) = rust_sysroot::get_commits_and_skipped(&start, &end, &tester.config)?;
+        (start, end, commits, skipped)
     };
+    if matches.is_present("expand_range") {
+        commits = expand_range(&tester, &client, commits, text_output)?;
+        start = commits[0].sha.clone();
+        end = commits[commits.len() - 1].sha.clone();
+        // The widened range was just listed, so this only reads it back from
+        // the cache.
+        skipped_authors = rust_sysroot::get_commits_and_skipped(&start, &end, &tester.config)?.1;
+    }
+    if let Some(path) = matches.value_of_os("skip_commits") {
+        commits = skip_commits(commits, Path::new(path))?;
+    }
+    if let Some(since) = since {
+        commits.retain(|commit| commit.date >= since);
+        if let Some(ref mut skipped) = skipped_authors {
+            skipped.retain(|commit| commit.date >= since);
+        }
+        match commits.first() {
+            Some(first) => start = first.sha.clone(),
+            None => bail!("no commits were merged between {} and {}", since.to_rfc2822(), end),
+        }
+    }
+    // A listed range was already checked, and a local rustc needs no artifacts.
+    if !matches.is_present("commit_range_file") && tester.local_rustc.is_none()
+        && !matches.is_present("list_missing_artifacts") {
+        commits = check_range_artifacts(&tester, commits, text_output)?;
+        let last = commits[commits.len() - 1].clone();
+        if let Some(ref mut skipped) = skipped_authors {
+            skipped.retain(|commit| commit.date <= last.date);
+        }
+        end = last.sha;
+    }
+    if !matches.is_present("commit_range_file") {
+        print_skipped_authors(&tester.config.merge_bots, skipped_authors.as_ref().map(|s| &s[..]), text_output);
+    }
+    if let Some(ref std_sha) = tester.config.std_sha {
+        warn_std_distance(&client, std_sha, &commits);
+    }
+    if !matches.is_present("any_order") {
+        check_chronological(&commits).chain_err(|| "the search would give a wrong result; \
+            pass --any-order if the test is monotonic in this order anyway")?;
+    }
 
-    let start = matches.value_of("start").unwrap();
-    let end = matches.value_of("end").unwrap();
-    let commits = rust_sysroot::get_commits(start, end)?;
+    if matches.is_present("list_missing_artifacts") {
+        list_missing_artifacts(&tester, &commits)?;
+        return Ok(0);
+    }
 
-    println!("Searching in {} commits; about {} steps",
-        commits.len(),
-        commits.len().next_power_of_two().trailing_zeros());
+    if text_output {
+        println!("Searching in {} commits; about {} steps",
+            commits.len(),
+            steps_for(commits.len()));
+    }
 
-    let found = least_satisfying(&commits, |commit| {
-        test_commit(commit, &test_case, &triple, preserve_sysroots).unwrap()
-    });
+    if matches.is_present("dry_run") {
+        // Which commits get probed after the first depends on the results, so
+        // simply assume that every commit works.
+        let mut probed = Vec::new();
+        least_satisfying(&commits, |commit| {
+            probed.push(commit.clone());
+            false
+        });
+        println!("Would test these commits, assuming each of them works:");
+        for commit in &probed {
+            println!("{} from {}: {}", commit.sha, commit.date.to_rfc2822(), commit.summary);
+        }
+        return Ok(0);
+    }
 
-    println!("searched commits {} through {}", commits.first().unwrap().sha, commits.last().unwrap().sha);
-    println!("regression in {:?}; {:?}", found, commits.get(found));
+    if let Some(path) = matches.value_of_os("emit_git_bisect") {
+        // Each search appends its commands.
+        File::create(path).chain_err(|| format!("failed to create {}", Path::new(path).display()))?;
+    }
+    let reports = if matches.is_present("each_triple") {
+        let mut reports = Vec::new();
+        for triple in &tester.triples {
+            if text_output {
+                println!("bisecting for {}:", triple);
+            }
+            let tester = Tester { triples: vec![triple.clone()], ..tester.clone() };
+            let session = format!("session-{}.json", triple);
+            reports.push(search_commits(&matches, &tester, &client, &commits, &start, &end, &session)?);
+        }
+        reports
+    } else {
+        vec![search_commits(&matches, &tester, &client, &commits, &start, &end, "session.json")?]
+    };
+    if json_output {
+        if matches.is_present("each_triple") {
+            println!("{}", serde_json::to_string_pretty(&reports).unwrap());
+        } else {
+            println!("{}", serde_json::to_string_pretty(&reports[0]).unwrap());
+        }
+    }
+    print_download_stats(&tester);
 
     Ok(0)
 }
+
+// Bisects `commits` with `tester`, printing the result unless the output is
+// JSON, and returns the JSON report. The results of tested commits are saved
+// in the session file named `session` in the cache directory.
+fn search_commits(matches: &ArgMatches, tester: &Tester, client: &Client, commits: &[Commit], start: &str, end: &str,
+                  session: &str) -> Result<serde_json::Value> {
+    let json_output = matches.value_of("output") == Some("json");
+    let triage_output = matches.value_of("output") == Some("triage");
+    let session_path = tester.config.cache_dir.join(session);
+    if matches.is_present("reset") && session_path.exists() {
+        fs::remove_file(&session_path)?;
+    }
+    let mut session = Session::load(&session_path, &tester.test_case, &tester.test_args, start, end,
+        &tester.triples)?;
+
+    let prefetch = matches.is_present("prefetch");
+    let mut prefetches = HashMap::new();
+
+    let progress = matches.is_present("progress");
+    let explain_steps = matches.is_present("explain");
+    // How many commits were actually tested rather than replayed from the
+    // session, and how long that took; the ETA is based on these.
+    let mut tested = 0;
+    let mut testing_time = Duration::from_secs(0);
+
+    // The indices of the commits skipped so far, which are left in the
+    // window but never probed again, so they don't count towards the steps
+    // left.
+    let mut skipped_probes = Vec::new();
+
+    let mut step = 0;
+    // Every probed commit, and whether it was found to be after the
+    // transition; replayed by `--emit-git-bisect`.
+    let mut probes = Vec::new();
+    let (found, skipped_indices) = try_least_satisfying_skipping(commits, |commit, probe| -> Result<Option<bool>> {
+        step += 1;
+        let step_start = Instant::now();
+        let Probe { index: probe, window } = probe;
+        if explain_steps {
+            let mid = window.0 + (window.1 - window.0) / 2;
+            eprintln!("step {}: searching commits [{}, {}) of {}; testing {} ({}) at {}, {}",
+                step, window.0, window.1, commits.len(), &commit.sha[..9], commit.date.format("%Y-%m-%d"), probe,
+                if probe == mid { "the middle" } else { "next to the middle, as commits there were skipped" });
+        }
+
+        // Whatever the result for this commit, one of these two is tested
+        // next. Downloading a sysroot which ends up unused only costs
+        // bandwidth, since the archives are kept in the cache anyway.
+        if prefetch {
+            for &broken in &[true, false] {
+                let next = match next_probe(window.0, window.1, probe, broken) {
+                    Some(next) => commits[next].clone(),
+                    None => continue,
+                };
+                if session.outcomes.contains_key(&next.sha) || prefetches.contains_key(&next.sha) {
+                    continue;
+                }
+                let (triples, config) = (tester.triples.clone(), tester.config.clone());
+                let sha = next.sha.clone();
+                prefetches.insert(sha, thread::spawn(move || {
+                    triples.iter().try_for_each(|triple| Sysroot::prefetch(&next, triple, &config))
+                }));
+            }
+        }
+        if let Some(handle) = prefetches.remove(&commit.sha) {
+            finish_prefetch(&commit.sha, handle);
+        }
+
+        let recorded = session.outcomes.contains_key(&commit.sha);
+        let outcome = session.outcome(&session_path, tester, commit)?;
+        if !recorded {
+            tested += 1;
+            testing_time += step_start.elapsed();
+        }
+        let holds = tester.is_found(outcome);
+        // What's left to search after this commit.
+        let window = match holds {
+            Some(true) => (window.0, probe),
+            Some(false) => (probe + 1, window.1),
+            None => {
+                skipped_probes.push(probe);
+                window
+            }
+        };
+        if explain_steps {
+            let label = match tester.find {
+                Find::Regression => "regression",
+                Find::Fix => "fix",
+            };
+            match holds {
+                Some(true) => eprintln!("  {} is {}, so the {} is at or before it; keeping [{}, {})",
+                    probe, tester.find.state(), label, window.0, window.1),
+                Some(false) => eprintln!("  {} is not {}, so the {} is after it; keeping [{}, {})",
+                    probe, tester.find.state(), label, window.0, window.1),
+                None => eprintln!("  {} could not be tested, so it's skipped; keeping [{}, {})",
+                    probe, window.0, window.1),
+            }
+        }
+        let remaining = window.1 - window.0
+            - skipped_probes.iter().filter(|&&i| window.0 <= i && i < window.1).count();
+        if progress {
+            print_progress(step, remaining, tested, testing_time);
+        } else if holds.is_none() && !json_output {
+            eprintln!("skipped {}, about {} steps left", commit.sha, steps_for(remaining));
+        }
+        probes.push((commit.sha.clone(), holds));
+        Ok(holds)
+    })?;
+    if let Some(path) = matches.value_of_os("emit_git_bisect") {
+        let path = Path::new(path);
+        OpenOptions::new().append(true).open(path)
+            .and_then(|mut file| file.write_all(git_bisect_log(tester, commits, &probes).as_bytes()))
+            .chain_err(|| format!("failed to write {}", path.display()))?;
+    }
+    let transition = Transition::new(found, commits.len());
+    let skipped = skipped_indices.iter().map(|&i| &commits[i]).collect::<Vec<_>>();
+    for (sha, handle) in prefetches {
+        finish_prefetch(&sha, handle);
+    }
+
+    let contradictory = if matches.is_present("validate") {
+        validate(commits, found, |commit| Ok(tester.is_found(tester.test(commit)?.0)))?
+    } else {
+        Vec::new()
+    };
+
+    let transitions = if matches.is_present("keep_going") {
+        scan(commits, |commit| Ok(tester.is_found(session.outcome(&session_path, tester, commit)?)))?
+    } else {
+        Vec::new()
+    };
+
+    let report = Report {
+        kind: tester.find,
+        triples: &tester.triples,
+        found: match transition {
+            Transition::Found(found) => Some(&commits[found]),
+            _ => None,
+        },
+        transition,
+        start: commits.first().unwrap(),
+        end: commits.last().unwrap(),
+        steps: step,
+        inconclusive: !matches!(transition, Transition::Found(_)),
+        contradictory,
+        pr: match transition {
+            Transition::Found(found) => explain(client, &commits[found]),
+            _ => None,
+        },
+        transitions,
+        skipped,
+        fallback_downloads: sysroot::fallback_downloads(),
+        measurements: match tester.perf_threshold {
+            Some(threshold) => commits.iter()
+                .filter_map(|commit| session.measurements.get(&commit.sha).map(|&value| Measurement {
+                    commit,
+                    value,
+                    slow: value > threshold,
+                }))
+                .collect(),
+            None => Vec::new(),
+        },
+    };
+    if triage_output {
+        print_triage(&report);
+    } else if !json_output {
+        println!("searched commits {} through {}", commits.first().unwrap().sha, commits.last().unwrap().sha);
+        let label = match tester.find {
+            Find::Regression => "regression",
+            Find::Fix => "fix",
+        };
+        match transition {
+            Transition::Found(found) => println!("{} in {:?}; {:?}", label, found, commits[found]),
+            Transition::AllPass => println!("no {} in range: none of the commits is {}",
+                label, tester.find.state()),
+            Transition::AllFail => println!("no {} in range: the first commit {} is already {}",
+                label, commits[0].sha, tester.find.state()),
+        }
+        if let Some(ref pr) = report.pr {
+            println!("merged PR #{}: {} (by {})", pr.number, pr.title, pr.author);
+        }
+        if let Some(commit) = report.found {
+            for triple in &tester.triples {
+                let log = tester.log_path(commit, triple);
+                if tester.capture && log.exists() {
+                    print_log_tail(&log)?;
+                }
+            }
+        }
+        if !report.skipped.is_empty() {
+            println!("skipped {} commits which could not be tested:", report.skipped.len());
+            for commit in &report.skipped {
+                println!("    {} from {}", commit.sha, commit.date.to_rfc2822());
+            }
+            if found > 0 && skipped_indices.contains(&(found - 1)) {
+                println!("warning: commits right before the one found were skipped, \
+                          so it may have been any of them");
+            }
+        }
+        if matches.is_present("keep_going") {
+            println!("the result changed at {} commits:", report.transitions.len());
+            for commit in &report.transitions {
+                println!("    {} from {}: {}", commit.sha, commit.date.to_rfc2822(), commit.summary);
+            }
+            if report.transitions.len() > 1 {
+                println!("warning: the result changed more than once, so the test is not monotonic \
+                          or there are several causes in this range");
+            }
+        }
+        if let Some(threshold) = tester.perf_threshold {
+            // Every measurement, so that the step change shows and not just
+            // where it crossed the threshold.
+            println!("measurements of the tested commits (threshold {}):", threshold);
+            for measurement in &report.measurements {
+                println!("    {} from {}: {}{}", measurement.commit.sha, measurement.commit.date.to_rfc2822(),
+                    measurement.value, if measurement.slow { " (slow)" } else { "" });
+            }
+        }
+        if !report.fallback_downloads.is_empty() {
+            println!("note: some modules were downloaded from a fallback URL:");
+            for from in &report.fallback_downloads {
+                println!("    {}: {}", from.module, from.url);
+            }
+        }
+        if !report.contradictory.is_empty() {
+            println!("warning: the test is not monotonic, so this result is unreliable; \
+                      re-testing gave contradictory results for:");
+            for commit in &report.contradictory {
+                println!("    {} from {}", commit.sha, commit.date.to_rfc2822());
+            }
+        }
+    }
+
+    Ok(serde_json::to_value(&report).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn quorum_is_broken() {
+        let runs = [true, false, true];
+        assert!(Quorum::Any.is_broken(&runs, 3));
+        assert!(!Quorum::All.is_broken(&runs, 3));
+        assert!(Quorum::Majority.is_broken(&runs, 3));
+        assert!(!Quorum::Majority.is_broken(&[true, false], 4));
+        assert!(Quorum::All.is_broken(&[true, true], 2));
+    }
+
+    #[test]
+    fn quorum_is_decided() {
+        // Any stops at the first broken run, all at the first passing one.
+        assert!(Quorum::Any.is_decided(&[false, true], 5));
+        assert!(!Quorum::Any.is_decided(&[false, false], 5));
+        assert!(Quorum::All.is_decided(&[true, false], 5));
+        assert!(!Quorum::All.is_decided(&[true, true], 5));
+        // A majority of 5 is 3 broken runs; 3 passing ones rule it out.
+        assert!(!Quorum::Majority.is_decided(&[true, true, false], 5));
+        assert!(Quorum::Majority.is_decided(&[true, true, false, true], 5));
+        assert!(Quorum::Majority.is_decided(&[false, true, false, false], 5));
+        // With 4, 2 passing runs already rule it out.
+        assert!(Quorum::Majority.is_decided(&[false, false], 4));
+    }
+
+    #[test]
+    fn parse_since_durations() {
+        let ago = |since: &str| {
+            let date = parse_since(since).unwrap();
+            (Utc::now() - date).num_days()
+        };
+        assert_eq!(ago("14d"), 14);
+        assert_eq!(ago("2w"), 14);
+        assert_eq!(ago("0d"), 0);
+        assert!(parse_since("twod").is_err());
+        assert!(parse_since("2m").is_err());
+    }
+
+    #[test]
+    fn parse_since_date() {
+        assert_eq!(parse_since("2018-07-01").unwrap().to_rfc3339(), "2018-07-01T00:00:00+00:00");
+        assert!(parse_since("07/01/2018").is_err());
+    }
+
+    fn commit(sha: &str) -> Commit {
+        Commit { sha: sha.to_string(), date: Utc::now(), summary: String::new() }
+    }
+
+    #[test]
+    fn skip_commits_by_prefix() {
+        let path = env::temp_dir().join(format!("bisect-rust-skip-{}", process::id()));
+        fs::write(&path, "# flaky\n\nabc12\n  def4567890  \nfff\n").unwrap();
+        let commits = ["abc1234", "abc9999", "def4567890", "0123456"].iter().map(|sha| commit(sha)).collect();
+        let kept = skip_commits(commits, &path).unwrap();
+        assert_eq!(kept.iter().map(|c| &c.sha[..]).collect::<Vec<_>>(), vec!["abc9999", "0123456"]);
+        assert!(skip_commits(vec![commit("abc1234")], &path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn declared_triple_in_script() {
+        let script = "#!/bin/sh\n# BISECT_TRIPLE=i686-unknown-linux-gnu\ncargo build\n";
+        assert_eq!(declared_triple(script, false), Some("i686-unknown-linux-gnu".to_string()));
+        let script = "export BISECT_TRIPLE=\"x86_64-pc-windows-gnu\";\n";
+        assert_eq!(declared_triple(script, false), Some("x86_64-pc-windows-gnu".to_string()));
+        assert_eq!(declared_triple("BISECT_TRIPLE=$TARGET\n", false), None);
+        // Only manifests may set it with `target`.
+        assert_eq!(declared_triple("target = \"wasm32-unknown-unknown\"\n", false), None);
+    }
+
+    #[test]
+    fn declared_triple_in_manifest() {
+        let config = "[build]\ntarget = \"wasm32-unknown-unknown\"\n";
+        assert_eq!(declared_triple(config, true), Some("wasm32-unknown-unknown".to_string()));
+        assert_eq!(declared_triple("aarch64-apple-darwin\n", true), Some("aarch64-apple-darwin".to_string()));
+        assert_eq!(declared_triple("[build]\ntarget-dir = \"out\"\n", true), None);
+    }
+
+    // Every way a search of up to 40 commits can go without skips: the probes
+    // `next_probe` predicts are the ones taken, and there are at most
+    // `steps_for` of them.
+    #[test]
+    fn next_probe_and_steps_for() {
+        assert_eq!(steps_for(0), 0);
+        assert_eq!(steps_for(1), 1);
+        assert_eq!(steps_for(8), 4);
+        for len in 0..40 {
+            let indices = (0..len).collect::<Vec<_>>();
+            let mut most_probes = 0;
+            for first_broken in 0..=len {
+                let mut probes = Vec::new();
+                least_satisfying(&indices, |&i| {
+                    probes.push(i);
+                    i >= first_broken
+                });
+                let (mut window, mut predicted) = ((0, len), if len > 0 { Some(len / 2) } else { None });
+                for &probe in &probes {
+                    assert_eq!(predicted, Some(probe));
+                    let broken = probe >= first_broken;
+                    predicted = next_probe(window.0, window.1, probe, broken);
+                    window = if broken { (window.0, probe) } else { (probe + 1, window.1) };
+                }
+                assert_eq!(predicted, None);
+                most_probes = cmp::max(most_probes, probes.len());
+            }
+            assert_eq!(most_probes as u32, steps_for(len));
+        }
+    }
+
+    #[test]
+    fn widening_span_doubles() {
+        let (first, mut last) = (commit("a"), commit("b"));
+        last.date = first.date + chrono::Duration::days(3);
+        let spans = (1..=MAX_EXPANSIONS).map(|i| widening_span(&first, &last, i).num_days()).collect::<Vec<_>>();
+        assert_eq!(spans, vec![3, 6, 12, 24, 48, 96]);
+        // A range of a single day's commits still moves by whole days.
+        last.date = first.date + chrono::Duration::hours(2);
+        assert_eq!(widening_span(&first, &last, 1).num_days(), 1);
+        assert_eq!(widening_span(&first, &last, 3).num_days(), 4);
+    }
+
+    #[test]
+    fn verdict_parse() {
+        assert_eq!(Verdict::parse("good\n"), Some(Verdict::Good));
+        assert_eq!(Verdict::parse("  abort "), Some(Verdict::Abort));
+        assert_eq!(Verdict::parse("Bad"), None);
+        assert_eq!(Verdict::parse("all good"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn last_verdict_printed_wins() {
+        let mut child = Command::new("sh").arg("-c").arg("echo bad; echo metric: 1.5; echo building; echo skip")
+            .stdout(Stdio::piped()).spawn().unwrap();
+        let printed = last_printed(pass_on_printed(child.stdout.take().unwrap(), Box::new(io::sink())));
+        child.wait().unwrap();
+        assert_eq!(printed.verdict, Some(Verdict::Skip));
+        assert_eq!(printed.metric, Some(1.5));
+    }
+}
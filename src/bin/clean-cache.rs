@@ -0,0 +1,69 @@
+#![recursion_limit = "1024"]
+
+#[macro_use] extern crate error_chain;
+#[macro_use] extern crate clap;
+extern crate env_logger;
+extern crate rust_sysroot;
+
+mod errors {
+    error_chain! {
+        links {
+            Utils(::rust_sysroot::errors::Error, ::rust_sysroot::errors::ErrorKind);
+        }
+    }
+}
+
+use errors::*;
+
+quick_main!(run);
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use rust_sysroot::cache;
+use rust_sysroot::sysroot;
+
+fn run() -> Result<i32> {
+    env_logger::init();
+
+    let matches = clap_app!(clean_cache =>
+       (version: "0.1")
+       (author: "The Rust Infrastructure Team")
+       (about: "Remove sysroots and saved archives from the cache")
+       (@arg cache_dir: +takes_value --("cache-dir") "directory to clean [default: $BISECT_CACHE or cache]")
+       (@arg archives_only: --("archives-only") conflicts_with[sysroots_only]
+        "only remove saved archives, keeping the extracted sysroots")
+       (@arg sysroots_only: --("sysroots-only") "only remove extracted sysroots, keeping the saved archives")
+       (@arg older_than: +takes_value --("older-than")
+        "only remove what wasn't used for this long, e.g. 12h, 14d or 2w")
+    ).get_matches();
+
+    let cache_dir = matches.value_of_os("cache_dir").map(PathBuf::from)
+        .unwrap_or_else(sysroot::default_cache_dir);
+    let older_than = match matches.value_of("older_than") {
+        Some(age) => Some(parse_age(age)?),
+        None => None,
+    };
+    let (removed, freed) = cache::clean(&cache_dir,
+        !matches.is_present("archives_only"),
+        !matches.is_present("sysroots_only"),
+        older_than)?;
+    println!("removed {} sysroots and archives from {}, freeing {} MB",
+        removed, cache_dir.display(), freed / 1_000_000);
+
+    Ok(0)
+}
+
+// Parses the argument to `--older-than`, a number of hours, days or weeks.
+fn parse_age(age: &str) -> Result<Duration> {
+    let unit = match age.chars().last() {
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        Some('w') => 7 * 24 * 60 * 60,
+        _ => bail!("invalid duration {}, expected e.g. 12h, 14d or 2w", age),
+    };
+    match age[..age.len() - 1].parse::<u64>() {
+        Ok(n) => Ok(Duration::from_secs(n * unit)),
+        Err(_) => bail!("invalid duration {}, expected e.g. 12h, 14d or 2w", age),
+    }
+}
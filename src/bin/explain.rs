@@ -0,0 +1,44 @@
+#![recursion_limit = "1024"]
+
+#[macro_use] extern crate error_chain;
+#[macro_use] extern crate clap;
+extern crate env_logger;
+extern crate reqwest;
+extern crate rust_sysroot;
+
+mod errors {
+    error_chain! {
+        links {
+            Utils(::rust_sysroot::errors::Error, ::rust_sysroot::errors::ErrorKind);
+        }
+    }
+}
+
+use errors::*;
+
+quick_main!(run);
+
+use reqwest::Client;
+
+use rust_sysroot::github;
+
+fn run() -> Result<i32> {
+    env_logger::init();
+
+    let matches = clap_app!(explain =>
+       (version: "0.1")
+       (author: "The Rust Infrastructure Team")
+       (about: "Find the PR a commit on master merged, e.g. the one found by bisect")
+       (@arg commit: +required +takes_value "SHA of the commit")
+    ).get_matches();
+
+    let client = Client::new();
+    let commit = github::get_commit(&client, matches.value_of("commit").unwrap())?;
+    println!("{} from {}: {}", commit.sha, commit.date.to_rfc2822(), commit.summary);
+    match github::pr_for_commit(&client, &commit)? {
+        Some(pr) => println!("merged PR #{}: {} (by {})", pr.number, pr.title, pr.author),
+        None => println!("not a merge commit of a PR"),
+    }
+
+    Ok(0)
+}
@@ -3,7 +3,7 @@
 #[macro_use] extern crate error_chain;
 #[macro_use] extern crate clap;
 extern crate env_logger;
-extern crate chrono;
+extern crate reqwest;
 extern crate rust_sysroot;
 
 mod errors {
@@ -18,9 +18,11 @@ use errors::*;
 
 quick_main!(run);
 
-use rust_sysroot::sysroot::Sysroot;
-use rust_sysroot::git::Commit;
-use chrono::{Utc, TimeZone};
+use std::path::PathBuf;
+
+use rust_sysroot::sysroot::{self, Sysroot};
+use rust_sysroot::git::{self, Commit};
+use rust_sysroot::github;
 use rust_sysroot::{get_host_triple, EPOCH_COMMIT};
 
 fn run() -> Result<i32> {
@@ -33,29 +35,96 @@ fn run() -> Result<i32> {
        (@arg commit: --commit +takes_value +required "SHA of sysroot")
        (@arg skip_validation: --("skip-validation") "skip validation of commit, useful for try builds")
        (@arg triple: +takes_value --triple "triple to use for downloads")
+       (@arg jobs: +takes_value -j --jobs default_value("3") "number of sysroot modules to download in parallel")
+       (@arg download_retries: +takes_value --("download-retries") default_value("3")
+        "number of times to retry a download after a transient error")
+       (@arg cache_dir: +takes_value --("cache-dir") "directory to install into [default: $BISECT_CACHE or cache]")
+       (@arg max_cache_size: +takes_value --("max-cache-size")
+        "size in MB to keep the cache under by evicting the least recently used sysroots")
+       (@arg free_space_factor: +takes_value --("free-space-factor") default_value("4")
+        "fail before extracting an archive unless this many times its size is free in the cache directory; 0 to not check")
+       (@arg mirror: +takes_value +multiple number_of_values(1) --mirror
+        "mirror of the CI artifacts to try first; a base URL or a template using @SHA@, @MODULE@ and @TRIPLE@ [default: $RUSTC_ARTIFACT_MIRROR]")
+       (@arg channel: +takes_value --channel possible_value[nightly beta stable] default_value[nightly]
+        "release channel the commit was built for")
+       (@arg cargo_sha: +takes_value --("cargo-sha")
+        "commit to install cargo from, instead of the one given with --commit")
+       (@arg std_sha: +takes_value --("std-sha")
+        "commit to install rust-std from, instead of the one given with --commit")
+       (@arg merge_bot: +takes_value +multiple number_of_values(1) --("merge-bot")
+        "also accept commits by this author as merge commits on master, besides bors")
+       (@arg no_local_repo: --("no-local-repo")
+        "validate the commit through the GitHub API instead of a local clone of rust-lang/rust")
+       (@arg verify_sysroot: --("verify-sysroot") "check that the sysroot can compile and run an empty program")
+       (@arg components: +takes_value --components default_value("rustc,rust-std,cargo")
+        "comma-separated modules to install, e.g. rustc,rust-std for tests that never run cargo")
+       (@arg offline: --offline "only use the archives saved in the cache directory, never download any")
     ).get_matches();
 
     let triple = match matches.value_of("triple") {
         Some(x) => x.to_string(),
         None => get_host_triple()?,
     };
+    let config = sysroot::Config {
+        cache_dir: matches.value_of_os("cache_dir").map(PathBuf::from)
+            .unwrap_or_else(sysroot::default_cache_dir),
+        jobs: value_t!(matches, "jobs", usize).unwrap_or_else(|e| e.exit()),
+        retries: value_t!(matches, "download_retries", u32).unwrap_or_else(|e| e.exit()),
+        try_build: matches.is_present("skip_validation"),
+        max_cache_size: if matches.is_present("max_cache_size") {
+            Some(value_t!(matches, "max_cache_size", u64).unwrap_or_else(|e| e.exit()) * 1_000_000)
+        } else {
+            None
+        },
+        mirrors: matches.values_of("mirror").map(|mirrors| mirrors.map(String::from).collect())
+            .unwrap_or_else(sysroot::default_mirrors),
+        channel: value_t!(matches, "channel", sysroot::Channel).unwrap_or_else(|e| e.exit()),
+        cargo_sha: matches.value_of("cargo_sha").map(String::from),
+        std_sha: matches.value_of("std_sha").map(String::from),
+        merge_bots: git::merge_bots(matches.values_of("merge_bot").into_iter().flatten()),
+        local_repo: !matches.is_present("no_local_repo"),
+        offline: matches.is_present("offline"),
+        sysroot_dir: None,
+        refresh_commits: false,
+        pass_env: Vec::new(),
+        env_overrides: Vec::new(),
+        rustc_flags: Vec::new(),
+        free_space_factor: value_t!(matches, "free_space_factor", u64).unwrap_or_else(|e| e.exit()),
+        progress: sysroot::stderr_is_tty(),
+        components: sysroot::parse_components(matches.value_of("components").unwrap())?,
+    };
+    if matches.is_present("verify_sysroot") && !config.components.iter().any(|c| c == "rust-std") {
+        bail!("--verify-sysroot compiles a program, so it needs the rust-std component");
+    }
     let commit = matches.value_of("commit").unwrap();
-    let commit = if !matches.is_present("skip_validation") {
-        let commits = rust_sysroot::get_commits(EPOCH_COMMIT, "master")?;
+    let commit = if matches.is_present("skip_validation") {
+        Commit::try_build(commit)
+    } else if config.channel != sysroot::Channel::Nightly {
+        // Commits of release branches aren't among the merges on master.
+        github::get_commit(&reqwest::Client::new(), commit)?
+    } else {
+        let commits = rust_sysroot::get_commits(EPOCH_COMMIT, "master", &config)?;
         commits.into_iter()
             .find(|c| c.sha.starts_with(commit))
             .expect("commit passed to be bors commit")
-    } else {
-        Commit {
-            sha: commit.to_string(),
-            date: Utc.ymd(2000, 1, 1).and_hms(0, 0, 0),
-            summary: String::new(),
-        }
     };
 
-    let _sysroot = Sysroot::install(&commit, &triple, false, true)?;
+    let sysroot = Sysroot::install(&commit, &triple, false, true, &config)?;
+    if matches.is_present("verify_sysroot") {
+        sysroot.verify()?;
+    }
 
-    println!("Sysroot can be found in cache/{}", commit.sha);
+    println!("Installed {} for {}:", commit.sha, sysroot.triple);
+    println!("  rustc:   {}", sysroot.rustc.display());
+    println!("  rustdoc: {}", sysroot.rustdoc.display());
+    if let Some(ref cargo) = sysroot.cargo {
+        let fallback = if sysroot.used_fallback_cargo { "fallback, " } else { "" };
+        println!("  cargo:   {} ({}from {})", cargo.display(), fallback, sysroot.cargo_sha);
+    }
+    if sysroot.std_sha != sysroot.sha && config.components.iter().any(|c| c == "rust-std") {
+        println!("  rust-std from {}", sysroot.std_sha);
+    }
+    println!("Sysroot can be found in {}", config.cache_dir.join(&commit.sha).display());
     println!("Please delete it when finished.");
 
     Ok(0)
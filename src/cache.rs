@@ -0,0 +1,216 @@
+//! Keep the size of the sysroot cache in check.
+//!
+//! The cache holds extracted sysroots (`<sha>/`), the cargos shared between
+//! them (`cargo/<sha>/`) and saved archives (`<sha>-<triple>-<module>.tar.*`).
+//! Next to each of these is a `.last-used` marker which is touched whenever
+//! it's used, so that the least recently used ones can be evicted first.
+//!
+//! Saved archives are stored once in `archives/`, named by their SHA-256
+//! digest, and hard linked (or copied) to their names; the `.sha256` file next
+//! to each name says which one it is. Identical archives are thus only kept
+//! once, and an archive whose published checksum is already stored needn't be
+//! downloaded again. Stored archives no name refers to anymore are removed
+//! along with the last one.
+//! Sysroots locked by another bisection sharing the cache (see `locks/`) are
+//! never evicted.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use errors::{Result, ResultExt};
+use is_full_sha;
+
+/// The directory saved archives are stored in by their digest.
+const STORE_DIR: &str = "archives";
+
+/// Where the archive with the SHA-256 `digest` is stored in `cache_dir`.
+pub(crate) fn stored_path(cache_dir: &Path, digest: &str) -> PathBuf {
+    cache_dir.join(STORE_DIR).join(digest)
+}
+
+fn marker_path(entry: &Path) -> PathBuf {
+    let mut path = OsString::from(entry.as_os_str());
+    path.push(".last-used");
+    PathBuf::from(path)
+}
+
+/// Records that the sysroot or archive at `entry` was just used.
+pub fn touch(entry: &Path) -> Result<()> {
+    let marker = marker_path(entry);
+    File::create(&marker).chain_err(|| format!("failed to touch {}", marker.display()))?;
+    Ok(())
+}
+
+fn last_used(entry: &Path) -> SystemTime {
+    fs::metadata(marker_path(entry))
+        .or_else(|_| fs::symlink_metadata(entry))
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+/// The total size of the files at or below `path`.
+fn size(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += self::size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+fn is_evictable(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    if path.is_dir() {
+        is_full_sha(name)
+    } else {
+        name.contains(".tar.") && !name.ends_with(".last-used") && !name.ends_with(".sha256")
+            && !name.ends_with(".part")
+    }
+}
+
+/// Removes a sysroot or archive from the cache, along with its markers.
+pub(crate) fn remove(entry: &Path) -> io::Result<()> {
+    if entry.is_dir() {
+        fs::remove_dir_all(entry)?;
+    } else {
+        fs::remove_file(entry)?;
+        let _ = fs::remove_file(::sysroot::checksum_path(entry));
+    }
+    let _ = fs::remove_file(marker_path(entry));
+    Ok(())
+}
+
+// Removes the stored archives in `cache_dir` which none of the saved archives'
+// checksum files refer to anymore. The store is listed before the checksum
+// files are read, so that an archive being saved meanwhile is never mistaken
+// for an unreferenced one.
+pub(crate) fn remove_unreferenced(cache_dir: &Path) -> Result<()> {
+    let store = cache_dir.join(STORE_DIR);
+    if !store.is_dir() {
+        return Ok(());
+    }
+    let stored = fs::read_dir(&store)?.map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut referenced = HashSet::new();
+    for entry in fs::read_dir(cache_dir)? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(".sha256") {
+            if let Ok(digest) = fs::read_to_string(&path) {
+                referenced.insert(digest.trim().to_string());
+            }
+        }
+    }
+    for path in stored {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if !referenced.contains(&name) {
+            debug!("removing unreferenced stored archive {}", path.display());
+            match fs::remove_file(&path) {
+                // Another process or thread sharing the cache got to it first.
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                result => result.chain_err(|| format!("failed to remove {}", path.display()))?,
+            }
+        }
+    }
+    Ok(())
+}
+
+// The sysroots, cargos and archives in `cache_dir` which could be removed,
+// leaving out those which belong to one of the `in_use` SHAs or whose
+// sysroot is locked.
+fn removable_entries(cache_dir: &Path, in_use: &[String]) -> Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    let cargo_dir = cache_dir.join("cargo");
+    let mut dirs = vec![cache_dir];
+    if cargo_dir.is_dir() {
+        dirs.push(&cargo_dir);
+    }
+    for dir in dirs {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if is_evictable(&path) && !in_use.iter().any(|sha| name.starts_with(&sha[..]))
+                && !::sysroot::is_locked(cache_dir, name.get(..40).unwrap_or(&name)) {
+                entries.push(path);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Evicts the least recently used sysroots and archives from `cache_dir`
+/// until it takes up at most `max_size` bytes. Anything belonging to one of
+/// the `in_use` SHAs, or whose sysroot is locked, is left alone.
+pub fn evict(cache_dir: &Path, max_size: u64, in_use: &[String]) -> Result<()> {
+    if !cache_dir.exists() {
+        return Ok(());
+    }
+    remove_unreferenced(cache_dir)?;
+    // Every stored archive is linked to at least once, and counted there.
+    let store = cache_dir.join(STORE_DIR);
+    let stored_size = if store.is_dir() { size(&store)? } else { 0 };
+    let mut total = size(cache_dir)?.saturating_sub(stored_size);
+    if total <= max_size {
+        return Ok(());
+    }
+
+    let mut entries = removable_entries(cache_dir, in_use)?.into_iter()
+        .map(|path| (last_used(&path), path))
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    for (_, entry) in entries {
+        if total <= max_size {
+            break;
+        }
+        let entry_size = size(&entry)?;
+        info!("evicting {} ({} MB) from the cache", entry.display(), entry_size / 1_000_000);
+        remove(&entry).chain_err(|| format!("failed to evict {}", entry.display()))?;
+        total = total.saturating_sub(entry_size);
+    }
+    remove_unreferenced(cache_dir)?;
+    if total > max_size {
+        warn!("cache {} is still {} MB after evicting everything not in use",
+            cache_dir.display(), total / 1_000_000);
+    }
+    Ok(())
+}
+
+/// Removes the extracted sysroots (along with their cargos) and/or the saved
+/// archives from `cache_dir`, only those last used longer than `older_than`
+/// ago if it's given. Sysroots locked by a running bisection are left alone.
+/// Returns how many were removed, and how many bytes that freed.
+pub fn clean(cache_dir: &Path, sysroots: bool, archives: bool, older_than: Option<Duration>)
+             -> Result<(usize, u64)> {
+    if !cache_dir.exists() {
+        return Ok((0, 0));
+    }
+    let now = SystemTime::now();
+    let (mut removed, mut freed) = (0, 0);
+    for entry in removable_entries(cache_dir, &[])? {
+        let wanted = if entry.is_dir() { sysroots } else { archives };
+        let old_enough = match older_than {
+            Some(age) => now.duration_since(last_used(&entry)).map(|used| used > age).unwrap_or(false),
+            None => true,
+        };
+        if !wanted || !old_enough {
+            continue;
+        }
+        let entry_size = size(&entry)?;
+        info!("removing {} ({} MB)", entry.display(), entry_size / 1_000_000);
+        remove(&entry).chain_err(|| format!("failed to remove {}", entry.display()))?;
+        removed += 1;
+        freed += entry_size;
+    }
+    remove_unreferenced(cache_dir)?;
+    Ok((removed, freed))
+}
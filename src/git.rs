@@ -1,9 +1,9 @@
 //! Get git commits with help of the libgit2 library
 
 const RUST_SRC_URL: &str = "https://github.com/rust-lang/rust";
-const RUST_SRC_REPO: Option<&str> = option_env!("RUST_SRC_REPO");
 
-use std::path::Path;
+use std::env;
+use std::path::{Path, PathBuf};
 
 use chrono::{DateTime, TimeZone, Utc};
 use git2::{Repository, Commit as Git2Commit};
@@ -11,7 +11,7 @@ use git2::build::RepoBuilder;
 
 use errors::Result;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Commit {
     pub sha: String,
     pub date: DateTime<Utc>,
@@ -19,6 +19,17 @@ pub struct Commit {
 }
 
 impl Commit {
+    /// Creates a commit for a try build. These aren't on master, so the real
+    /// date and summary aren't known; the commit is dated now so that it gets
+    /// treated like any other recent commit.
+    pub fn try_build(sha: &str) -> Self {
+        Commit {
+            sha: sha.to_string(),
+            date: Utc::now(),
+            summary: String::from("(try build)"),
+        }
+    }
+
     // Takes &mut because libgit2 internally caches summaries
     fn from_git2_commit(commit: &mut Git2Commit) -> Self {
         Commit {
@@ -38,67 +49,201 @@ fn lookup_rev<'rev>(repo: &'rev Repository, rev: &str) -> Result<Git2Commit<'rev
 
 fn get_repo() -> Result<Repository> {
     let loc = Path::new("rust.git");
-    match (RUST_SRC_REPO, loc.exists()) {
-        (Some(_), _) | (_, true) => {
-            let repo = Repository::open(RUST_SRC_REPO.map(Path::new).unwrap_or(loc))?;
-            {
-                let mut remote = repo.find_remote("origin").or_else(|_| repo.remote_anonymous("origin"))?;
-                remote.fetch(&["master"], None, None)?;
-            }
-            Ok(repo)
-        }
+    let rust_src_repo = env::var_os("RUST_SRC_REPO").map(PathBuf::from);
+    match (rust_src_repo, loc.exists()) {
+        (Some(path), _) => open_repo(&path),
+        (None, true) => open_repo(loc),
         (None, false) => {
-            Ok(RepoBuilder::new().bare(true).clone(RUST_SRC_URL, Path::new("rust.git"))?)
+            Ok(RepoBuilder::new().bare(true).clone(RUST_SRC_URL, loc)?)
         }
     }
 }
 
-/// Returns the bors merge commits between the two specified boundaries
-/// (boundaries inclusive).
-pub fn get_commits_between(first_commit: &str, last_commit: &str) -> Result<Vec<Commit>> {
+// Opens an existing clone of rust-lang/rust, bringing it up to date.
+fn open_repo(path: &Path) -> Result<Repository> {
+    let repo = Repository::open(path)?;
+    {
+        let mut remote = repo.find_remote("origin").or_else(|_| repo.remote_anonymous("origin"))?;
+        // The tags are needed to bisect between releases.
+        remote.fetch(&["master", "+refs/tags/*:refs/tags/*"], None, None)?;
+    }
+    Ok(repo)
+}
+
+/// The authors of the merge commits on master. More can be accepted with
+/// `merge_bots`.
+pub const MERGE_BOTS: &[&str] = &["bors"];
+
+/// Returns the accepted merge commit authors: `MERGE_BOTS` and `extra`.
+pub fn merge_bots<'a, I: IntoIterator<Item = &'a str>>(extra: I) -> Vec<String> {
+    MERGE_BOTS.iter().cloned().chain(extra).map(String::from).collect()
+}
+
+fn first_parent<'rev>(commit: &Git2Commit<'rev>) -> Result<Git2Commit<'rev>> {
+    match commit.parents().next() {
+        Some(parent) => Ok(parent),
+        None => bail!("{} has no parent, so the walk reached the root of the repo \
+                       without encountering the first commit; it is an ancestor, but \
+                       not on the first-parent history", commit.id()),
+    }
+}
+
+/// Looks up each of `revs` (e.g. SHAs) in a clone of rust-lang/rust.
+pub fn lookup_commits(revs: &[&str]) -> Result<Vec<Commit>> {
     let repo = get_repo()?;
-    let mut first = lookup_rev(&repo, first_commit)?;
-    let last = lookup_rev(&repo, last_commit)?;
-
-    // Sanity check -- our algorithm below only works reliably if the
-    // two commits are merge commits made by bors
-    let assert_by_bors = |c: &Git2Commit| -> Result<()> {
-        match c.author().name() {
-            Some("bors") => Ok(()),
-            Some(author) => bail!("Expected author {} to be bors for {}", author, c.id()),
-            None => bail!("No author for {}", c.id()),
-        }
+    revs.iter().map(|rev| Ok(Commit::from_git2_commit(&mut lookup_rev(&repo, rev)?))).collect()
+}
+
+/// Returns the merge commits between the two specified boundaries
+/// (boundaries inclusive), i.e. those authored by one of `merge_bots`.
+pub fn get_commits_between(first_commit: &str, last_commit: &str, merge_bots: &[String])
+                           -> Result<(Vec<Commit>, Vec<Commit>)> {
+    commits_between(&get_repo()?, first_commit, last_commit, merge_bots)
+}
+
+fn commits_between(repo: &Repository, first_commit: &str, last_commit: &str, merge_bots: &[String])
+                   -> Result<(Vec<Commit>, Vec<Commit>)> {
+    let mut first = lookup_rev(repo, first_commit)?;
+    let last = lookup_rev(repo, last_commit)?;
+    // Otherwise the walk below would only end at the root.
+    if first.id() != last.id() && !repo.graph_descendant_of(last.id(), first.id())? {
+        bail!("{} is not an ancestor of {}, so the range is empty", first_commit, last_commit);
+    }
+
+    let is_merge = |c: &Git2Commit| match c.author().name() {
+        Some(author) => merge_bots.iter().any(|bot| bot == author),
+        None => false,
     };
-    assert_by_bors(&first)?;
-    assert_by_bors(&last)?;
+    // Our algorithm below only works reliably if the two commits are merge
+    // commits, but a boundary by another author only costs some accuracy.
+    if !is_merge(&first) {
+        warn!("Expected author {:?} of {} to be one of {}", first.author().name(), first.id(),
+            merge_bots.join(", "));
+    }
     // Now find the commits
     // We search from the last and always take the first of its parents,
     // to only get merge commits.
-    // This uses the fact that all bors merge commits have the earlier
+    // This uses the fact that all merge commits have the earlier
     // merge commit as their first parent.
     let mut res = Vec::new();
-    let mut current = last;
+    let mut skipped = Vec::new();
+    let mut current = last.clone();
+    if !is_merge(&current) && current.id() != first.id() {
+        warn!("Expected author {:?} of {} to be one of {}, starting from the merge before it",
+            current.author().name(), current.id(), merge_bots.join(", "));
+    }
     loop {
-        assert_by_bors(&current)?;
-        res.push(Commit::from_git2_commit(&mut current));
-        match current.parents().next() {
-            Some(c) => {
-                if c.author().name() != Some("bors") {
-                    debug!("{:?} has non-bors author: {:?}, skipping", c.id(), c.author().name());
-                    current = c.parents().next().unwrap();
-                    continue;
-                }
-                current = c;
-                if current.id() == first.id() {
-                    // Reached the first commit, our end of the search.
-                    break;
-                }
-            },
-            None => bail!("reached end of repo without encountering the first commit"),
+        while !is_merge(&current) && current.id() != first.id() {
+            debug!("{:?} has non-merge author: {:?}, skipping", current.id(), current.author().name());
+            skipped.push(Commit::from_git2_commit(&mut current));
+            current = first_parent(&current)?;
+        }
+        if current.id() == first.id() {
+            // Reached the first commit, our end of the search.
+            break;
         }
+        res.push(Commit::from_git2_commit(&mut current));
+        current = first_parent(&current)?;
     }
     res.push(Commit::from_git2_commit(&mut first));
     // Reverse in order to obtain chronological order
     res.reverse();
-    Ok(res)
+    skipped.reverse();
+    Ok((res, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use git2::{Oid, Signature, Time};
+
+    static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+
+    /// A repo in a fresh temporary directory, removed again on drop.
+    struct Fixture {
+        path: PathBuf,
+        repo: Repository,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let path = env::temp_dir().join(format!("bisect-rust-git-{}-{}", process::id(),
+                FIXTURES.fetch_add(1, Ordering::SeqCst)));
+            let repo = Repository::init_bare(&path).unwrap();
+            Fixture { path, repo }
+        }
+
+        fn commit(&self, author: &str, day: i64, message: &str, parents: &[Oid]) -> Oid {
+            let sig = Signature::new(author, "someone@example.com", &Time::new(day * 86400, 0)).unwrap();
+            let tree = self.repo.find_tree(self.repo.treebuilder(None).unwrap().write().unwrap()).unwrap();
+            let parents = parents.iter().map(|&id| self.repo.find_commit(id).unwrap()).collect::<Vec<_>>();
+            let parents = parents.iter().collect::<Vec<_>>();
+            self.repo.commit(None, &sig, &sig, message, &tree, &parents).unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    /// Builds master as bors would, with a commit pushed directly between
+    /// the second and third merge. Returns the commits on master, oldest
+    /// first, and the PR commit merged by the second merge.
+    fn history(fixture: &Fixture) -> (Vec<Oid>, Oid) {
+        let first = fixture.commit("bors", 1, "Auto merge of #1", &[]);
+        let pr = fixture.commit("alice", 2, "Fix the thing", &[first]);
+        let second = fixture.commit("bors", 3, "Auto merge of #2", &[first, pr]);
+        let direct = fixture.commit("alice", 4, "Pushed directly", &[second]);
+        let third = fixture.commit("bors", 5, "Auto merge of #3", &[direct]);
+        (vec![first, second, direct, third], pr)
+    }
+
+    fn shas(commits: &[Commit]) -> Vec<String> {
+        commits.iter().map(|commit| commit.sha.clone()).collect()
+    }
+
+    #[test]
+    fn merges_and_skipped() {
+        let fixture = Fixture::new();
+        let (master, _) = history(&fixture);
+        let bots = merge_bots(None);
+        let (commits, skipped) = commits_between(&fixture.repo, &master[0].to_string(),
+            &master[3].to_string(), &bots).unwrap();
+        assert_eq!(shas(&commits), vec![master[0].to_string(), master[1].to_string(), master[3].to_string()]);
+        assert_eq!(shas(&skipped), vec![master[2].to_string()]);
+        assert_eq!(commits[1].summary, "Auto merge of #2");
+    }
+
+    #[test]
+    fn single_commit() {
+        let fixture = Fixture::new();
+        let (master, _) = history(&fixture);
+        let sha = master[1].to_string();
+        let (commits, skipped) = commits_between(&fixture.repo, &sha, &sha, &merge_bots(None)).unwrap();
+        assert_eq!(shas(&commits), vec![sha]);
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn reversed_range() {
+        let fixture = Fixture::new();
+        let (master, _) = history(&fixture);
+        let err = commits_between(&fixture.repo, &master[3].to_string(), &master[0].to_string(),
+            &merge_bots(None)).unwrap_err();
+        assert!(err.to_string().contains("is not an ancestor"), "{}", err);
+    }
+
+    #[test]
+    fn start_off_the_first_parent_history() {
+        let fixture = Fixture::new();
+        let (master, pr) = history(&fixture);
+        let err = commits_between(&fixture.repo, &pr.to_string(), &master[3].to_string(),
+            &merge_bots(None)).unwrap_err();
+        assert!(err.to_string().contains("not on the first-parent history"), "{}", err);
+    }
 }
@@ -0,0 +1,329 @@
+//! Query the GitHub API for information about rust-lang/rust.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, TimeZone, Utc};
+use reqwest::{Client, Response, StatusCode};
+use reqwest::header::{AUTHORIZATION, USER_AGENT};
+use serde_json::Value;
+
+use git::Commit;
+
+use errors::Result;
+
+const API_URL: &str = "https://api.github.com/repos/rust-lang/rust";
+const WEB_URL: &str = "https://github.com/rust-lang/rust";
+
+/// The longest we wait for the rate limit to reset before giving up.
+const MAX_RATE_LIMIT_WAIT: u64 = 15 * 60;
+
+fn get(client: &Client, url: &str) -> Result<Value> {
+    loop {
+        debug!("requesting: {}", url);
+        let mut req = client.get(url).header(USER_AGENT, "rust-lang/bisect-rust");
+        match env::var("GH_API_TOKEN") {
+            Ok(ref token) if !token.is_empty() => {
+                req = req.header(AUTHORIZATION, format!("token {}", token));
+            }
+            _ => {}
+        }
+        let mut resp = req.send()?;
+        if let Some(reset) = rate_limit_reset(&resp) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let wait = reset.saturating_sub(now) + 1;
+            if wait > MAX_RATE_LIMIT_WAIT {
+                let until = match Utc.timestamp_opt(reset as i64, 0).single() {
+                    Some(until) => until.to_rfc2822(),
+                    None => format!("{} (seconds since the epoch)", reset),
+                };
+                bail!("GitHub API rate limit exceeded until {}; set GH_API_TOKEN for a higher limit", until);
+            }
+            warn!("GitHub API rate limit exceeded, waiting {}s for it to reset", wait);
+            thread::sleep(Duration::from_secs(wait));
+            continue;
+        }
+        if !resp.status().is_success() {
+            bail!("GitHub API request to {} failed: {}", url, resp.status());
+        }
+        return Ok(resp.json()?);
+    }
+}
+
+// If `resp` was refused because the rate limit is used up, returns when it
+// resets, in seconds since the epoch.
+fn rate_limit_reset(resp: &Response) -> Option<u64> {
+    let status = resp.status();
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let header = |name| resp.headers().get(name).and_then(|value| value.to_str().ok());
+    if header("x-ratelimit-remaining") != Some("0") {
+        return None;
+    }
+    header("x-ratelimit-reset").and_then(|reset| reset.parse().ok())
+}
+
+/// Converts a commit object as returned by the GitHub commits API. Any other
+/// shape of JSON, e.g. an error object, is an error rather than a panic.
+fn parse_commit(commit: &Value) -> Result<Commit> {
+    if !commit.is_object() {
+        bail!("expected a commit object, got: {}", commit);
+    }
+    let sha = match commit["sha"].as_str() {
+        Some(sha) => sha,
+        None => match commit["message"].as_str() {
+            Some(message) => bail!("expected a commit object, got an error: {}", message),
+            None => bail!("commit object has no sha"),
+        },
+    };
+    let date = match commit["commit"]["committer"]["date"].as_str() {
+        Some(date) => date,
+        None => bail!("commit {} has no committer date", sha),
+    };
+    let message = commit["commit"]["message"].as_str().unwrap_or("");
+    Ok(Commit {
+        sha: sha.to_string(),
+        date: match date.parse::<DateTime<Utc>>() {
+            Ok(date) => date,
+            Err(err) => bail!("invalid committer date {:?} for {}: {}", date, sha, err),
+        },
+        summary: message.lines().next().unwrap_or("").to_string(),
+    })
+}
+
+/// Resolves a pull request number to the bors merge commit that landed it on
+/// master.
+pub fn commit_for_pr(client: &Client, pr: u32) -> Result<Commit> {
+    info!("Resolving PR #{} to its merge commit...", pr);
+    let pull = get(client, &format!("{}/pulls/{}", API_URL, pr))?;
+    if pull["merged"].as_bool() != Some(true) {
+        bail!("PR #{} was never merged", pr);
+    }
+    match pull["base"]["ref"].as_str() {
+        Some("master") => {}
+        Some(base) => bail!("PR #{} was merged into {}, not master", pr, base),
+        None => bail!("PR #{} has no base branch", pr),
+    }
+    let sha = match pull["merge_commit_sha"].as_str() {
+        Some(sha) => sha,
+        None => bail!("PR #{} is merged but has no merge commit", pr),
+    };
+    parse_commit(&get(client, &format!("{}/commits/{}", API_URL, sha))?)
+}
+
+/// Fetches a commit of rust-lang/rust by its SHA.
+pub fn get_commit(client: &Client, sha: &str) -> Result<Commit> {
+    parse_commit(&get(client, &format!("{}/commits/{}", API_URL, sha))?)
+}
+
+/// Returns the merge commits between the two specified boundaries
+/// (boundaries inclusive), i.e. those authored by one of `merge_bots`, like
+/// `git::get_commits_between` but without needing a local clone.
+///
+/// Also like it, the commits by other authors on the first-parent history
+/// between them are returned too, except with a single merge bot: then only
+/// its commits are listed, so the others are unknown.
+///
+/// Up to `jobs` pages of commits are requested at once, which may fetch a few
+/// pages past the first commit for nothing.
+pub fn get_commits_between(client: &Client, first_commit: &str, last_commit: &str,
+                           merge_bots: &[String], jobs: usize) -> Result<(Vec<Commit>, Option<Vec<Commit>>)> {
+    let first = get(client, &format!("{}/commits/{}", API_URL, first_commit))?;
+    let first_author = first["commit"]["author"]["name"].as_str().unwrap_or("").to_string();
+    let first = parse_commit(&first)?;
+    // Only the commits since the first one are listed, and if there's a single
+    // merge bot GitHub can filter by it too, which saves a lot of pages.
+    let mut url = format!("{}/commits?sha={}&since={}&per_page=100", API_URL, last_commit,
+        first.date.to_rfc3339());
+    if merge_bots.len() == 1 {
+        url.push_str(&format!("&author={}", merge_bots[0]));
+    }
+
+    let mut res = Vec::new();
+    // The commits by other authors, and every listed commit's first parent,
+    // to tell which of them are on the first-parent history.
+    let mut others = Vec::new();
+    let mut first_parents = HashMap::new();
+    let mut head = None;
+    let mut found = false;
+    let mut page = 1;
+    'pages: loop {
+        // The pages are numbered, so the next few can be requested at once;
+        // they're still gone through in order.
+        let handles = (page..page + cmp::max(jobs, 1)).map(|page| {
+            let client = client.clone();
+            let page_url = format!("{}&page={}", url, page);
+            thread::spawn(move || {
+                let commits = get(&client, &page_url);
+                (page_url, commits)
+            })
+        }).collect::<Vec<_>>();
+        let mut pages = Vec::new();
+        for handle in handles {
+            pages.push(handle.join().unwrap_or_else(|_| (url.clone(), Err("page request thread panicked".into()))));
+        }
+        page += pages.len();
+
+        for (page_url, commits) in pages {
+            let commits = commits?;
+            let commits = match commits.as_array() {
+                Some(commits) if commits.is_empty() => break 'pages,
+                Some(commits) => commits,
+                None => bail!("expected a list of commits from {}, got: {}", page_url, commits),
+            };
+            for commit in commits {
+                let author = commit["commit"]["author"]["name"].as_str().unwrap_or("");
+                let first_parent = commit["parents"][0]["sha"].as_str().map(String::from);
+                let commit = parse_commit(commit)?;
+                if head.is_none() {
+                    head = Some(commit.sha.clone());
+                }
+                if let Some(parent) = first_parent {
+                    first_parents.insert(commit.sha.clone(), parent);
+                }
+                if commit.sha == first.sha {
+                    found = true;
+                    break 'pages;
+                }
+                if merge_bots.iter().any(|bot| bot == author) {
+                    res.push(commit);
+                } else {
+                    debug!("{} has non-merge author: {}, skipping", commit.sha, author);
+                    others.push(commit);
+                }
+            }
+        }
+    }
+    // Filtering by author hides a first commit by anyone else, but otherwise
+    // it must have been listed if it's in the history of the last one.
+    let filtered_out = merge_bots.len() == 1 && merge_bots[0] != first_author;
+    if !found && !filtered_out {
+        bail!("{} is not an ancestor of {}, so the range is empty", first_commit, last_commit);
+    }
+    let skipped = if merge_bots.len() == 1 {
+        None
+    } else {
+        // Pull requests' own commits are listed too, but aren't on the
+        // first-parent history the merges form.
+        let mut on_history = HashSet::new();
+        let mut sha = head;
+        while let Some(current) = sha {
+            if current == first.sha {
+                break;
+            }
+            sha = first_parents.get(&current).cloned();
+            on_history.insert(current);
+        }
+        others.retain(|commit| on_history.contains(&commit.sha));
+        others.reverse();
+        Some(others)
+    };
+    res.push(first);
+    // Reverse in order to obtain chronological order
+    res.reverse();
+    Ok((res, skipped))
+}
+
+/// The link to the commit `sha` of rust-lang/rust on GitHub.
+pub fn commit_url(sha: &str) -> String {
+    format!("{}/commit/{}", WEB_URL, sha)
+}
+
+/// The link to the PR `number` of rust-lang/rust on GitHub.
+pub fn pr_url(number: u32) -> String {
+    format!("{}/pull/{}", WEB_URL, number)
+}
+
+/// A pull request which landed on master.
+#[derive(Debug, Clone, Serialize)]
+pub struct PullRequest {
+    pub number: u32,
+    pub title: String,
+    pub author: String,
+}
+
+/// The summary prefixes of merge commits, each followed by the PR number.
+const MERGE_PREFIXES: &[&str] = &["Auto merge of #", "Merge #", "Rollup merge of #"];
+
+/// Parses the number of the PR a merge commit landed out of its summary, e.g.
+/// `Auto merge of #12345 - user:branch, r=reviewer`.
+pub fn pr_number(summary: &str) -> Option<u32> {
+    let rest = MERGE_PREFIXES.iter().find(|prefix| summary.starts_with(*prefix))
+        .map(|prefix| &summary[prefix.len()..])?;
+    let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+/// Finds the PR `commit` merged, or `None` if its summary isn't that of a
+/// merge commit.
+pub fn pr_for_commit(client: &Client, commit: &Commit) -> Result<Option<PullRequest>> {
+    let number = match pr_number(&commit.summary) {
+        Some(number) => number,
+        None => return Ok(None),
+    };
+    let pull = get(client, &format!("{}/pulls/{}", API_URL, number))?;
+    Ok(Some(PullRequest {
+        number,
+        title: pull["title"].as_str().unwrap_or("").to_string(),
+        author: pull["user"]["login"].as_str().unwrap_or("").to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn json(text: &str) -> Value {
+        serde_json::from_str(text).unwrap()
+    }
+
+    fn commit_json(committer: &str) -> Value {
+        json(&format!(r#"{{
+            "sha": "c3db9023693f159537a0ed390e29465065fa393a",
+            "commit": {{
+                "committer": {},
+                "message": "Auto merge of #4 - someone:branch, r=someone\n\nDetails"
+            }}
+        }}"#, committer))
+    }
+
+    #[test]
+    fn parse() {
+        let commit = parse_commit(&commit_json(r#"{"date": "2020-01-06T12:30:00Z"}"#)).unwrap();
+        assert_eq!(commit.sha, "c3db9023693f159537a0ed390e29465065fa393a");
+        assert_eq!(commit.date, Utc.ymd(2020, 1, 6).and_hms(12, 30, 0));
+        assert_eq!(commit.summary, "Auto merge of #4 - someone:branch, r=someone");
+    }
+
+    #[test]
+    fn parse_non_object() {
+        assert!(parse_commit(&json(r#"["c3db9023693f159537a0ed390e29465065fa393a"]"#)).is_err());
+        assert!(parse_commit(&json(r#""c3db9023693f159537a0ed390e29465065fa393a""#)).is_err());
+        assert!(parse_commit(&Value::Null).is_err());
+    }
+
+    #[test]
+    fn parse_error_object() {
+        let err = parse_commit(&json(r#"{
+            "message": "No commit found for SHA: c3db902",
+            "documentation_url": "https://docs.github.com/rest/commits/commits#get-a-commit"
+        }"#)).unwrap_err();
+        assert!(err.to_string().contains("No commit found"), "{}", err);
+    }
+
+    #[test]
+    fn parse_null_committer() {
+        assert!(parse_commit(&commit_json("null")).is_err());
+    }
+
+    #[test]
+    fn parse_invalid_date() {
+        assert!(parse_commit(&commit_json(r#"{"date": "yesterday"}"#)).is_err());
+        assert!(parse_commit(&commit_json(r#"{"date": 1578313800}"#)).is_err());
+    }
+}
@@ -1,15 +1,24 @@
 #![recursion_limit = "1024"]
 
 extern crate serde;
+#[macro_use] extern crate serde_derive;
 extern crate serde_json;
 #[macro_use] extern crate error_chain;
 extern crate xz2;
+extern crate bzip2;
+extern crate zstd;
 extern crate flate2;
+extern crate hex;
+extern crate libc;
+extern crate sha2;
 extern crate tar;
+extern crate toml;
 #[macro_use] extern crate log;
 extern crate reqwest;
 extern crate git2;
 extern crate chrono;
+#[cfg(feature = "async-download")] extern crate futures;
+#[cfg(feature = "async-download")] extern crate tokio;
 
 pub mod errors {
     // Create the Error, ErrorKind, ResultExt, and Result types
@@ -19,22 +28,55 @@ pub mod errors {
             Reqwest(::reqwest::Error);
             Io(::std::io::Error);
         }
+
+        errors {
+            MissingArtifacts(sha: String, module: String) {
+                description("no artifacts were uploaded for the commit")
+                display("no artifacts were uploaded for {} of {}", module, sha)
+            }
+        }
     }
 }
 
+pub mod cache;
 pub mod git;
+pub mod github;
+pub mod nightly;
+pub mod overrides;
 pub mod sysroot;
 
+use std::fs;
+use std::path::Path;
 use std::process::Command;
 
 use errors::*;
 
+/// The triple this was built for, which is the host's unless it was cross
+/// compiled.
+const BUILD_TARGET: &str = env!("BISECT_BUILD_TARGET");
+
+/// Returns the triple of the host, as reported by the rustc in `PATH`. If
+/// there's no such rustc, or it doesn't report one, falls back to the triple
+/// this was built for.
 pub fn get_host_triple() -> Result<String> {
+    match rustc_host_triple() {
+        Ok(triple) => Ok(triple),
+        Err(err) => {
+            warn!("{}; assuming the host is {}, pass --triple otherwise", err, BUILD_TARGET);
+            Ok(BUILD_TARGET.to_string())
+        }
+    }
+}
+
+fn rustc_host_triple() -> Result<String> {
     let output = Command::new("rustc")
         .arg("-v").arg("-V").output()
-        .chain_err(|| format!("running rustc -vV to obtain host triple failed; try --triple"))?;
+        .chain_err(|| "running rustc -vV to obtain the host triple failed")?;
     let output = String::from_utf8_lossy(&output.stdout);
-    Ok(output.lines().find(|l| l.starts_with("host: ")).unwrap()[6..].to_string())
+    match output.lines().find(|l| l.starts_with("host: ")) {
+        Some(line) => Ok(line[6..].trim().to_string()),
+        None => bail!("rustc -vV did not report the host triple"),
+    }
 }
 
 /// The first commit which build artifacts are made available through the CI for
@@ -44,10 +86,400 @@ pub fn get_host_triple() -> Result<String> {
 /// artifact of this commit itself is no longer available.
 pub const EPOCH_COMMIT: &str = "927c55d86b0be44337f37cf5b0a76fb8ba86e06c";
 
-pub fn get_commits(start: &str, end: &str) -> Result<Vec<git::Commit>> {
-    info!("Getting commits from the git checkout in {}...{}", start, end);
-    let commits = git::get_commits_between(start, end)?;
-    assert_eq!(commits.first().expect("at least one commit").sha, start);
+/// Finds the index of the least item in `slice` for which the `predicate` holds.
+///
+/// The `predicate` is assumed to hold for every item after the first one it
+/// holds for. Returns `slice.len()` if it holds for none of them. Nothing
+/// checks this: for commits, it means they must be in chronological order,
+/// which `check_chronological` can verify.
+pub fn least_satisfying<T, P>(slice: &[T], mut predicate: P) -> usize
+    where P: FnMut(&T) -> bool
+{
+    match try_least_satisfying(slice, |item| Ok::<_, ()>(predicate(item))) {
+        Ok(index) => index,
+        Err(()) => unreachable!(),
+    }
+}
+
+/// Where the predicate of a search like `least_satisfying` starts to hold,
+/// e.g. where the commits start to be broken.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transition {
+    /// It holds from this index on, and not for the item before it.
+    Found(usize),
+    /// It holds for none of the items, so the transition is after them.
+    AllPass,
+    /// It already holds for the first item, so the transition is before
+    /// them.
+    AllFail,
+}
+
+impl Transition {
+    /// Classifies the index `least_satisfying` returned for a slice of `len`
+    /// items.
+    pub fn new(found: usize, len: usize) -> Transition {
+        if found == len {
+            Transition::AllPass
+        } else if found == 0 {
+            Transition::AllFail
+        } else {
+            Transition::Found(found)
+        }
+    }
+}
+
+/// Like `least_satisfying`, but stops at the first error the `predicate`
+/// returns.
+pub fn try_least_satisfying<T, P, E>(slice: &[T], mut predicate: P) -> std::result::Result<usize, E>
+    where P: FnMut(&T) -> std::result::Result<bool, E>
+{
+    let mut base = 0usize;
+    let mut s = slice;
+
+    loop {
+        let (head, tail) = s.split_at(s.len() >> 1);
+        if tail.is_empty() {
+            return Ok(base + head.len());
+        }
+        if predicate(&tail[0])? {
+            s = head;
+        } else {
+            base += head.len() + 1;
+            s = &tail[1..];
+        }
+    }
+}
+
+/// Where `try_least_satisfying_skipping` is in its search when it calls the
+/// predicate.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Probe {
+    /// The index of the item being probed.
+    pub index: usize,
+    /// The part `[lo, hi)` of the slice still being searched, which `index`
+    /// is in.
+    pub window: (usize, usize),
+}
+
+/// Like `try_least_satisfying`, but the `predicate` may return `None` for
+/// items it can't decide on, e.g. commits without artifacts. These are skipped
+/// in favour of the nearest item in the search window that can be decided on.
+/// The `predicate` is also told which item it's called for, and where in the
+/// search that is.
+///
+/// Returns the index of the least item found to satisfy the `predicate`,
+/// along with the indices of the skipped items. If some of the items just
+/// before the one found were skipped, the actual least one may be among them.
+pub fn try_least_satisfying_skipping<T, P, E>(slice: &[T], mut predicate: P)
+    -> std::result::Result<(usize, Vec<usize>), E>
+    where P: FnMut(&T, Probe) -> std::result::Result<Option<bool>, E>
+{
+    let (mut lo, mut hi) = (0, slice.len());
+    let mut skipped = Vec::new();
+    while lo < hi {
+        let mid = lo + ((hi - lo) >> 1);
+        // Try the items around `mid`, nearest first.
+        let candidates = (0..hi - lo)
+            .map(|i| if i % 2 == 0 { mid + i / 2 } else { mid.wrapping_sub(i / 2 + 1) })
+            .filter(|&i| lo <= i && i < hi);
+        let mut decided = None;
+        for probe in candidates {
+            if skipped.contains(&probe) {
+                continue;
+            }
+            match predicate(&slice[probe], Probe { index: probe, window: (lo, hi) })? {
+                Some(holds) => {
+                    decided = Some((probe, holds));
+                    break;
+                }
+                None => skipped.push(probe),
+            }
+        }
+        match decided {
+            Some((probe, true)) => hi = probe,
+            Some((probe, false)) => lo = probe + 1,
+            // Nothing left in the window can be decided on.
+            None => break,
+        }
+    }
+    skipped.sort();
+    Ok((hi, skipped))
+}
+
+/// Returns the merge commits from `start` to `end` (both inclusive), e.g.
+/// from `EPOCH_COMMIT` to `master`. Fails if `start` isn't in the history of
+/// `end`.
+///
+/// If both are full SHAs, the list is cached in the cache directory, since
+/// listing a wide range takes a while; see `Config::refresh_commits`.
+pub fn get_commits(start: &str, end: &str, config: &sysroot::Config) -> Result<Vec<git::Commit>> {
+    Ok(get_commits_and_skipped(start, end, config)?.0)
+}
+
+/// Like `get_commits`, but also returns the commits on the history between
+/// `start` and `end` which were skipped for not being by one of the merge
+/// bots, e.g. pushed to master directly, or `None` if that isn't known (see
+/// `github::get_commits_between`).
+pub fn get_commits_and_skipped(start: &str, end: &str, config: &sysroot::Config)
+                               -> Result<(Vec<git::Commit>, Option<Vec<git::Commit>>)> {
+    let cache_path = if is_full_sha(start) && is_full_sha(end) {
+        Some(config.cache_dir.join("commits").join(format!("{}-{}.json", start, end)))
+    } else {
+        None
+    };
+    if let Some(ref path) = cache_path {
+        if !config.refresh_commits {
+            if let Some(list) = load_commits(path, &config.merge_bots) {
+                info!("Using the commits in {}...{} listed in {}", start, end, path.display());
+                return Ok((list.commits, list.skipped));
+            }
+        }
+    }
+
+    let (commits, skipped) = if config.local_repo {
+        info!("Getting commits from the git checkout in {}...{}", start, end);
+        let (commits, skipped) = git::get_commits_between(start, end, &config.merge_bots)?;
+        (commits, Some(skipped))
+    } else {
+        info!("Getting commits from the GitHub API in {}...{}", start, end);
+        github::get_commits_between(&reqwest::Client::new(), start, end, &config.merge_bots, config.jobs)?
+    };
+
+    if let Some(ref path) = cache_path {
+        let list = CommitList { merge_bots: config.merge_bots.clone(), commits, skipped };
+        if let Err(err) = save_commits(path, &list) {
+            warn!("failed to cache the commit list in {}: {}", path.display(), err);
+        }
+        return Ok((list.commits, list.skipped));
+    }
+    Ok((commits, skipped))
+}
+
+/// Resolves `shas` to commits, keeping their order, either from the local
+/// clone or through the GitHub API as `config.local_repo` says. Fails if any
+/// of them doesn't exist.
+pub fn resolve_commits(shas: &[&str], config: &sysroot::Config) -> Result<Vec<git::Commit>> {
+    if config.local_repo {
+        info!("Looking up {} commits in the git checkout", shas.len());
+        return git::lookup_commits(shas);
+    }
+    info!("Looking up {} commits through the GitHub API", shas.len());
+    let client = reqwest::Client::new();
+    shas.iter().map(|sha| {
+        github::get_commit(&client, sha).chain_err(|| format!("failed to look up commit {}", sha))
+    }).collect()
+}
+
+/// Resolves `rev`, e.g. `master`, to the SHA of the commit it currently
+/// points to, so that a range ending there can be searched again later even
+/// after the branch moved on. Full SHAs are returned as is.
+pub fn resolve_rev(rev: &str, config: &sysroot::Config) -> Result<String> {
+    if is_full_sha(rev) {
+        return Ok(rev.to_string());
+    }
+    Ok(resolve_commits(&[rev], config)?.remove(0).sha)
+}
 
-    Ok(commits)
+// Whether `rev` is a full SHA rather than e.g. a branch, which may move. Only
+// lists between fixed commits are cached, and sysroots are named by theirs.
+pub(crate) fn is_full_sha(rev: &str) -> bool {
+    rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// A list of commits cached by `get_commits`. Which commits are listed also
+/// depends on the merge bots, so those are recorded too.
+#[derive(Serialize, Deserialize)]
+struct CommitList {
+    merge_bots: Vec<String>,
+    commits: Vec<git::Commit>,
+    /// See `get_commits_and_skipped`; unknown for lists cached before it.
+    #[serde(default)]
+    skipped: Option<Vec<git::Commit>>,
+}
+
+fn load_commits(path: &Path, merge_bots: &[String]) -> Option<CommitList> {
+    let file = fs::File::open(path).ok()?;
+    match serde_json::from_reader::<_, CommitList>(file) {
+        Ok(ref list) if list.merge_bots != merge_bots => None,
+        Ok(list) => Some(list),
+        Err(err) => {
+            warn!("ignoring unreadable commit list {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+fn save_commits(path: &Path, list: &CommitList) -> Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    let file = fs::File::create(path)?;
+    serde_json::to_writer(file, list).chain_err(|| format!("failed to write {}", path.display()))
+}
+
+/// Checks that `commits` are in chronological order, oldest first, as the
+/// searches like `least_satisfying` expect them to be. Commits with the same
+/// date may be in either order.
+pub fn check_chronological(commits: &[git::Commit]) -> Result<()> {
+    if let Some(pair) = commits.windows(2).find(|pair| pair[0].date > pair[1].date) {
+        bail!("commits are not in chronological order: {} from {} comes before {} from {}",
+            pair[0].sha, pair[0].date.to_rfc2822(), pair[1].sha, pair[1].date.to_rfc2822());
+    }
+    Ok(())
+}
+
+/// A range of commits to bisect, and how to install sysroots for them.
+#[derive(Debug, Clone)]
+pub struct BisectOptions {
+    /// The last commit known to work.
+    pub start: String,
+    /// The first commit known to be broken.
+    pub end: String,
+    /// How to list the commits, e.g. from the local clone or through the
+    /// GitHub API. Usually also passed to `sysroot::Sysroot::install` by the
+    /// predicate.
+    pub config: sysroot::Config,
+}
+
+/// The outcome of a bisection.
+#[derive(Debug, Clone)]
+pub struct BisectResult {
+    /// The commits that were searched, in chronological order.
+    pub commits: Vec<git::Commit>,
+    /// The index of the first broken commit in `commits`, or `commits.len()`
+    /// if none of them was broken.
+    pub found: usize,
+    /// How many commits were tested.
+    pub steps: usize,
+}
+
+impl BisectResult {
+    /// Whether the first broken commit is within `commits`, rather than all
+    /// of them working or being broken.
+    pub fn transition(&self) -> Transition {
+        Transition::new(self.found, self.commits.len())
+    }
+
+    /// The first broken commit, if any.
+    pub fn commit(&self) -> Option<&git::Commit> {
+        self.commits.get(self.found)
+    }
+}
+
+/// Bisects the commits between `opts.start` and `opts.end` for the first one
+/// for which `is_broken` returns true.
+///
+/// `is_broken` typically installs a sysroot for the commit with
+/// `sysroot::Sysroot::install`, for the triple it's testing, and runs a test
+/// with it.
+pub fn bisect<P, E>(opts: &BisectOptions, mut is_broken: P) -> std::result::Result<BisectResult, E>
+    where P: FnMut(&git::Commit) -> std::result::Result<bool, E>,
+          E: From<Error>
+{
+    let commits = get_commits(&opts.start, &opts.end, &opts.config)?;
+    let mut steps = 0;
+    let found = try_least_satisfying(&commits, |commit| {
+        steps += 1;
+        is_broken(commit)
+    })?;
+    Ok(BisectResult { commits, found, steps })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_satisfying_empty() {
+        assert_eq!(least_satisfying(&[] as &[u32], |_| true), 0);
+    }
+
+    #[test]
+    fn least_satisfying_all_true() {
+        assert_eq!(least_satisfying(&[1, 2, 3, 4, 5], |_| true), 0);
+    }
+
+    #[test]
+    fn least_satisfying_all_false() {
+        assert_eq!(least_satisfying(&[1, 2, 3, 4, 5], |_| false), 5);
+    }
+
+    #[test]
+    fn least_satisfying_transition() {
+        let items: Vec<u32> = (0..10).collect();
+        for at in 0..items.len() as u32 + 1 {
+            assert_eq!(least_satisfying(&items, |&i| i >= at), at as usize);
+        }
+    }
+
+    #[test]
+    fn try_least_satisfying_stops_at_error() {
+        let items: Vec<u32> = (0..10).collect();
+        let mut calls = 0;
+        let result = try_least_satisfying(&items, |_| {
+            calls += 1;
+            Err::<bool, _>("broken")
+        });
+        assert_eq!(result, Err("broken"));
+        assert_eq!(calls, 1);
+        assert_eq!(try_least_satisfying(&items, |&i| Ok::<_, ()>(i >= 7)), Ok(7));
+    }
+
+    #[test]
+    fn skipping_without_skips() {
+        let items: Vec<u32> = (0..10).collect();
+        for at in 0..items.len() as u32 + 1 {
+            let found = try_least_satisfying_skipping(&items, |&i, _| Ok::<_, ()>(Some(i >= at)));
+            assert_eq!(found, Ok((at as usize, vec![])));
+        }
+        assert_eq!(try_least_satisfying_skipping(&[] as &[u32], |_, _| Ok::<_, ()>(Some(true))),
+                   Ok((0, vec![])));
+    }
+
+    #[test]
+    fn skipping_around_undecidable() {
+        let items: Vec<u32> = (0..10).collect();
+        let (found, skipped) = try_least_satisfying_skipping(&items, |&i, _| {
+            Ok::<_, ()>(if i == 4 || i == 5 { None } else { Some(i >= 6) })
+        }).unwrap();
+        assert_eq!(found, 6);
+        assert!(skipped.iter().all(|&i| i == 4 || i == 5));
+        assert!(!skipped.is_empty());
+    }
+
+    #[test]
+    fn skipping_hides_the_transition() {
+        // The transition is at 5, which can't be decided on, so the search
+        // ends on the next decidable item and reports 5 as skipped.
+        let items: Vec<u32> = (0..10).collect();
+        let (found, skipped) = try_least_satisfying_skipping(&items, |&i, _| {
+            Ok::<_, ()>(if i == 5 { None } else { Some(i >= 5) })
+        }).unwrap();
+        assert_eq!(found, 6);
+        assert_eq!(skipped, vec![5]);
+    }
+
+    #[test]
+    fn skipping_reports_probes() {
+        let items: Vec<u32> = (0..10).collect();
+        let mut probes = Vec::new();
+        try_least_satisfying_skipping(&items, |&i, probe| {
+            assert_eq!(i as usize, probe.index);
+            probes.push(probe);
+            Ok::<_, ()>(if i == 5 { None } else { Some(i >= 3) })
+        }).unwrap();
+        assert_eq!(probes, vec![
+            Probe { index: 5, window: (0, 10) },
+            Probe { index: 4, window: (0, 10) },
+            Probe { index: 2, window: (0, 4) },
+            Probe { index: 3, window: (3, 4) },
+        ]);
+    }
+
+    #[test]
+    fn skipping_nothing_decidable() {
+        let items: Vec<u32> = (0..4).collect();
+        let (found, skipped) = try_least_satisfying_skipping(&items, |_, _| Ok::<_, ()>(None)).unwrap();
+        assert_eq!(found, items.len());
+        assert_eq!(skipped, vec![0, 1, 2, 3]);
+    }
 }
@@ -0,0 +1,88 @@
+//! Find out which commits nightly releases were built from.
+
+use chrono::{Duration, NaiveDate, Utc};
+use reqwest::Client;
+
+use github;
+
+use errors::Result;
+
+const MANIFEST_URL: &str = "https://static.rust-lang.org/dist/@DATE@/channel-rust-nightly.toml";
+
+/// How many days before a given date to look for a nightly, in case none was
+/// published on the day itself.
+const MAX_NIGHTLY_GAP: i64 = 7;
+
+/// Returns the SHA of the commit the nightly of `date` was built from, or
+/// `None` if there was no nightly that day.
+pub fn commit_for_nightly(client: &Client, date: NaiveDate) -> Result<Option<String>> {
+    let url = MANIFEST_URL.replace("@DATE@", &date.format("%Y-%m-%d").to_string());
+    debug!("requesting: {}", url);
+    let mut resp = client.get(&url).send()?;
+    if resp.status().is_client_error() {
+        return Ok(None);
+    }
+    if !resp.status().is_success() {
+        bail!("fetching {} failed: {}", url, resp.status());
+    }
+    match rustc_commit(&resp.text()?) {
+        Some(sha) => Ok(Some(sha)),
+        None => bail!("could not find the rustc commit in {}", url),
+    }
+}
+
+/// Returns the latest nightly published on or shortly before `date`, along
+/// with the commit it was built from.
+pub fn nightly_on_or_before(client: &Client, date: NaiveDate) -> Result<(NaiveDate, String)> {
+    for days in 0..MAX_NIGHTLY_GAP {
+        let nightly = date - Duration::days(days);
+        if let Some(sha) = commit_for_nightly(client, nightly)? {
+            return Ok((nightly, sha));
+        }
+    }
+    bail!("no nightly was published in the {} days up to {}", MAX_NIGHTLY_GAP, date)
+}
+
+/// How many days after a commit to look for a nightly containing it before
+/// giving up.
+const MAX_NIGHTLY_DELAY: i64 = 30;
+
+/// Returns the date of the first nightly which contains the commit `sha`, or
+/// `None` if none has been published yet.
+///
+/// Nightlies are built from master, whose history is linear, so a nightly
+/// contains every commit which was merged no later than the one it was built
+/// from.
+pub fn nightly_for_commit(client: &Client, sha: &str) -> Result<Option<NaiveDate>> {
+    let commit = github::get_commit(client, sha)?;
+    let today = Utc::today().naive_utc();
+    let first = commit.date.naive_utc().date();
+    let mut date = first;
+    while date <= today && date - first <= Duration::days(MAX_NIGHTLY_DELAY) {
+        if let Some(nightly_sha) = commit_for_nightly(client, date)? {
+            if nightly_sha == commit.sha || github::get_commit(client, &nightly_sha)?.date >= commit.date {
+                return Ok(Some(date));
+            }
+        }
+        date += Duration::days(1);
+    }
+    if date <= today {
+        bail!("no nightly in the {} days after {} contains it", MAX_NIGHTLY_DELAY, sha);
+    }
+    Ok(None)
+}
+
+// Finds the `git_commit_hash` in the `[pkg.rustc]` table of a channel
+// manifest.
+fn rustc_commit(manifest: &str) -> Option<String> {
+    let mut in_rustc = false;
+    for line in manifest.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_rustc = line == "[pkg.rustc]";
+        } else if in_rustc && line.starts_with("git_commit_hash") {
+            return line.split('"').nth(1).map(|sha| sha.to_string());
+        }
+    }
+    None
+}
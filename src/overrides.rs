@@ -0,0 +1,103 @@
+//! Environment overrides for the commits of some range, e.g. to pass a flag
+//! under its old name to the commits from before it was renamed, so that a
+//! single bisection can span the rename.
+//!
+//! They're read from a TOML file of `[[range]]` tables like
+//!
+//! ```toml
+//! [[range]]
+//! until = "2018-06-30"
+//! rustflags = ["-Zold-name"]
+//! env = { RUST_MIN_STACK = "16777216" }
+//! ```
+//!
+//! where a range is bounded by dates (`since` and `until`) or by commits
+//! (`first` and `last`), all inclusive, and applies to every commit if it
+//! isn't bounded at all.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use toml;
+
+use git::Commit;
+use sysroot::Config;
+
+use errors::{Result, ResultExt};
+
+#[derive(Deserialize)]
+struct OverridesFile {
+    #[serde(default, rename = "range")]
+    ranges: Vec<RangeSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RangeSpec {
+    since: Option<String>,
+    until: Option<String>,
+    first: Option<String>,
+    last: Option<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    rustflags: Vec<String>,
+}
+
+/// Extra environment for the commands run with the sysroots of the commits
+/// in a range.
+#[derive(Debug, Clone)]
+pub struct EnvOverride {
+    /// The date of the first commit it applies to, if any.
+    start: Option<DateTime<Utc>>,
+    /// The date just after the last commit it applies to, if any.
+    end: Option<DateTime<Utc>>,
+    /// Variables to set, over any passed on with `Config::pass_env`.
+    pub env: BTreeMap<String, String>,
+    /// Flags to add to `RUSTFLAGS`.
+    pub rustflags: Vec<String>,
+}
+
+impl EnvOverride {
+    /// Whether `commit` is in the range this applies to.
+    pub fn applies_to(&self, commit: &Commit) -> bool {
+        self.start.is_none_or(|start| start <= commit.date) && self.end.is_none_or(|end| commit.date < end)
+    }
+}
+
+/// Reads the overrides in the TOML file at `path`. The commits bounding
+/// ranges are looked up like those of a bisection, see `::resolve_commits`.
+pub fn load(path: &Path, config: &Config) -> Result<Vec<EnvOverride>> {
+    let contents = fs::read_to_string(path)
+        .chain_err(|| format!("failed to read the environment overrides from {}", path.display()))?;
+    let file: OverridesFile = toml::from_str(&contents)
+        .chain_err(|| format!("failed to parse the environment overrides in {}", path.display()))?;
+    file.ranges.into_iter().map(|spec| {
+        if spec.since.is_some() && spec.first.is_some() || spec.until.is_some() && spec.last.is_some() {
+            bail!("a range in {} is bounded by both a date and a commit on the same side", path.display());
+        }
+        let start = match (spec.since, spec.first) {
+            (Some(date), _) => Some(start_of(&date)?),
+            (_, Some(sha)) => Some(commit_date(&sha, config)?),
+            _ => None,
+        };
+        let end = match (spec.until, spec.last) {
+            (Some(date), _) => Some(start_of(&date)? + Duration::days(1)),
+            (_, Some(sha)) => Some(commit_date(&sha, config)? + Duration::seconds(1)),
+            _ => None,
+        };
+        Ok(EnvOverride { start, end, env: spec.env, rustflags: spec.rustflags })
+    }).collect()
+}
+
+fn start_of(date: &str) -> Result<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .chain_err(|| format!("invalid date {}, expected YYYY-MM-DD", date))?;
+    Ok(DateTime::from_utc(date.and_hms(0, 0, 0), Utc))
+}
+
+fn commit_date(sha: &str, config: &Config) -> Result<DateTime<Utc>> {
+    Ok(::resolve_commits(&[sha], config)?.remove(0).date)
+}
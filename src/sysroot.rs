@@ -1,32 +1,426 @@
 //! Download and manage sysroots.
 
+use std::cell::Cell;
+use std::cmp;
 use std::env;
 use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, BufRead, Read, BufReader};
+use std::io::{self, BufRead, Read, BufReader, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::ffi::OsStr;
+use std::process::{self, Command};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use chrono::{TimeZone, Utc};
 use flate2::bufread::GzDecoder;
 use xz2::bufread::XzDecoder;
-use reqwest;
+use bzip2::bufread::BzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+use hex;
+use reqwest::{self, Client, Method, StatusCode, Url};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT_RANGES, AUTHORIZATION, CONTENT_ENCODING, CONTENT_RANGE,
+                      CONTENT_TYPE, ETAG, IF_RANGE, RANGE, USER_AGENT};
+use sha2::{Digest, Sha256};
 use tar::Archive;
 
+use cache;
 use git::Commit;
+use overrides::EnvOverride;
 
-use errors::{Result, ResultExt};
+use errors::{Error, ErrorKind, Result, ResultExt};
+
+#[cfg(feature = "async-download")]
+mod async_download;
+
+/// Returns the cache directory to use when none is given explicitly: the
+/// `BISECT_CACHE` environment variable if it's set, and `cache` otherwise.
+pub fn default_cache_dir() -> PathBuf {
+    env::var_os("BISECT_CACHE").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("cache"))
+}
+
+/// Returns the mirrors to use when none are given explicitly: the
+/// comma-separated list in the `RUSTC_ARTIFACT_MIRROR` environment variable.
+///
+/// A mirror is either a base URL with the same layout as the CI bucket, or a
+/// URL template using `@SHA@`, `@MODULE@`, `@CHANNEL@` and `@TRIPLE@`.
+pub fn default_mirrors() -> Vec<String> {
+    env::var("RUSTC_ARTIFACT_MIRROR").ok().map(|mirrors| {
+        mirrors.split(',').map(str::trim).filter(|m| !m.is_empty()).map(String::from).collect()
+    }).unwrap_or_default()
+}
+
+/// The release channel the commits were built for, which is part of the
+/// artifact names.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Channel {
+    Nightly,
+    Beta,
+    Stable,
+}
+
+impl FromStr for Channel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Channel> {
+        match s {
+            "nightly" => Ok(Channel::Nightly),
+            "beta" => Ok(Channel::Beta),
+            "stable" => Ok(Channel::Stable),
+            _ => bail!("unknown channel: {}", s),
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Channel::Nightly => write!(f, "nightly"),
+            Channel::Beta => write!(f, "beta"),
+            Channel::Stable => write!(f, "stable"),
+        }
+    }
+}
+
+/// Settings shared by all sysroots installed during a run.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The directory sysroots are extracted into, and archives saved in.
+    pub cache_dir: PathBuf,
+    /// How many modules to download concurrently, and how many pages of
+    /// commits to request at once when listing them through the GitHub API.
+    pub jobs: usize,
+    /// How many times to retry a download which failed with a transient error.
+    pub retries: u32,
+    /// Whether the commits are try builds, which should be looked for in the
+    /// try bucket first.
+    pub try_build: bool,
+    /// The size in bytes the cache is kept under by evicting the least
+    /// recently used sysroots and archives before each download.
+    pub max_cache_size: Option<u64>,
+    /// Mirrors of the CI artifacts to try before the CI bucket itself, see
+    /// `default_mirrors`.
+    pub mirrors: Vec<String>,
+    /// The channel of the commits, i.e. of the branch they were merged into.
+    pub channel: Channel,
+    /// The commit to install cargo from for every sysroot, instead of the
+    /// commit's own or a known-good one if its cargo is broken.
+    pub cargo_sha: Option<String>,
+    /// The commit to install rust-std from for every sysroot, instead of the
+    /// commit's own, to tell whether a change is in the compiler or the
+    /// library. rustc only loads a std it's compatible with, so this is
+    /// unlikely to work for commits far apart.
+    pub std_sha: Option<String>,
+    /// The authors of merge commits on master, see `git::merge_bots`.
+    pub merge_bots: Vec<String>,
+    /// Whether to list commits from a local clone of rust-lang/rust, rather
+    /// than through the GitHub API.
+    pub local_repo: bool,
+    /// Whether to only use archives already saved in the cache directory,
+    /// never downloading anything.
+    pub offline: bool,
+    /// A directory of sysroots downloaded ahead of time, laid out as
+    /// `<sha>/rustc` and `<sha>/cargo`. A commit found there is used from
+    /// there as is, see `SysrootDownload::predownloaded`.
+    pub sysroot_dir: Option<PathBuf>,
+    /// The modules to install for each sysroot, out of `COMPONENTS`.
+    pub components: Vec<String>,
+    /// Whether to list the commits of a range again, rather than use the
+    /// list cached by an earlier `get_commits`.
+    pub refresh_commits: bool,
+    /// Whether to report the progress of downloads on stderr, e.g. if
+    /// `stderr_is_tty`.
+    pub progress: bool,
+    /// How many times the size of an archive must be free in the cache
+    /// directory before extracting it, or 0 to not check.
+    pub free_space_factor: u64,
+    /// Environment variables to pass on to the commands run with a sysroot,
+    /// whose environment is otherwise cleared.
+    pub pass_env: Vec<String>,
+    /// Extra environment for the commands run with the sysroots of some of
+    /// the commits, see `overrides`.
+    pub env_overrides: Vec<EnvOverride>,
+    /// Flags for every commit's rustc, see `Sysroot::rustflags`.
+    pub rustc_flags: Vec<String>,
+}
+
+/// Whether stderr is a terminal, so that progress can be shown there.
+#[cfg(unix)]
+pub fn stderr_is_tty() -> bool {
+    unsafe { ::libc::isatty(2) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn stderr_is_tty() -> bool {
+    false
+}
+
+/// The space in bytes available to unprivileged users on the file system
+/// `path` is on, or `None` if it can't be determined.
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: ::libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { ::libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Checks that there's room in `directory` to extract the archive of `size`
+/// bytes being downloaded from `url`, so that a full disk doesn't leave a
+/// half-extracted sysroot behind. How much it takes up once extracted varies,
+/// hence the generous `factor`.
+fn check_free_space(directory: &Path, factor: u64, url: &str, size: Option<u64>) -> Result<()> {
+    let size = match size {
+        Some(size) if factor > 0 => size,
+        _ => return Ok(()),
+    };
+    let available = match available_space(directory) {
+        Some(available) => available,
+        None => return Ok(()),
+    };
+    if available < size * factor {
+        bail!("only {} MB are free in {}, but extracting {} ({} MB) may take up to {} MB; \
+               free up space or lower --free-space-factor",
+            available / 1_000_000, directory.display(), url, size / 1_000_000, size * factor / 1_000_000);
+    }
+    Ok(())
+}
+
+/// The modules a sysroot can be installed from. rustc is always needed.
+pub const COMPONENTS: &[&str] = &["rustc", "rust-std", "cargo"];
+
+/// Parses a comma-separated list of modules to install, e.g. `rustc,rust-std`
+/// for tests that never run cargo.
+pub fn parse_components(list: &str) -> Result<Vec<String>> {
+    let mut components = Vec::new();
+    for component in list.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+        if !COMPONENTS.contains(&component) {
+            bail!("unknown component {}, expected one of {}", component, COMPONENTS.join(", "));
+        }
+        components.push(component.to_string());
+    }
+    if !components.iter().any(|c| c == "rustc") {
+        bail!("the rustc component is always needed");
+    }
+    // Keep the usual order, so that rustc starts downloading first.
+    Ok(COMPONENTS.iter().filter(|c| components.iter().any(|d| d == *c)).map(|c| c.to_string()).collect())
+}
+
+/// The SHAs of the sysroots alive in this process, which must not be evicted
+/// from the cache.
+static LIVE_SYSROOTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// The directories of the sysroots alive in this process which `Drop` would
+/// remove, so that they can be cleaned up when the process is interrupted and
+/// doesn't get to run it.
+static INTERRUPT_CLEANUP: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Removes the directories of the sysroots being installed or used, as their
+/// `Drop` would have. Meant for a signal handler, just before the process
+/// exits.
+pub fn remove_interrupted() {
+    let paths = mem::take(&mut *INTERRUPT_CLEANUP.lock().unwrap());
+    for path in paths.into_iter().filter(|path| path.exists()) {
+        info!("removing {}", path.display());
+        fs::remove_dir_all(&path).unwrap_or_else(|err| {
+            info!("failed to remove {:?}, please do so manually: {:?}", path, err);
+        });
+    }
+}
+
+/// Registers `paths` with `INTERRUPT_CLEANUP` for as long as it's alive.
+#[derive(Debug)]
+struct InterruptCleanup {
+    paths: Vec<PathBuf>,
+}
+
+impl InterruptCleanup {
+    fn new(paths: Vec<PathBuf>) -> InterruptCleanup {
+        INTERRUPT_CLEANUP.lock().unwrap().extend(paths.iter().cloned());
+        InterruptCleanup { paths }
+    }
+}
+
+impl Drop for InterruptCleanup {
+    fn drop(&mut self) {
+        let mut registered = INTERRUPT_CLEANUP.lock().unwrap();
+        for path in &self.paths {
+            if let Some(i) = registered.iter().position(|registered| registered == path) {
+                registered.remove(i);
+            }
+        }
+    }
+}
+
+/// An exclusive lock on a commit's sysroot in a cache directory, so that
+/// bisections sharing the cache don't extract into or remove a sysroot
+/// another one is using. It's a `flock` on `locks/<sha>.lock`, which is
+/// released when dropped or when the process exits; elsewhere than on unix
+/// it does nothing.
+#[derive(Debug)]
+struct SysrootLock {
+    _file: File,
+}
+
+impl SysrootLock {
+    /// Takes the lock on the sysroot of `sha`, waiting for whoever holds it
+    /// to be done with it.
+    fn acquire(cache_dir: &Path, sha: &str) -> Result<SysrootLock> {
+        let path = lock_path(cache_dir, sha);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let file = fs::OpenOptions::new().create(true).write(true).truncate(false).open(&path)
+            .chain_err(|| format!("failed to open lock file {}", path.display()))?;
+        if !lock(&file, false)? {
+            info!("waiting for another process to be done with the sysroot of {}", sha);
+            lock(&file, true).chain_err(|| format!("failed to lock {}", path.display()))?;
+        }
+        Ok(SysrootLock { _file: file })
+    }
+}
+
+fn lock_path(cache_dir: &Path, sha: &str) -> PathBuf {
+    cache_dir.join("locks").join(format!("{}.lock", sha))
+}
+
+/// Whether the sysroot of `sha` in `cache_dir` is locked, by this process or
+/// another one, and mustn't be evicted.
+pub fn is_locked(cache_dir: &Path, sha: &str) -> bool {
+    match File::open(lock_path(cache_dir, sha)) {
+        // Dropping the file releases the lock again if it was free.
+        Ok(file) => !lock(&file, false).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+// Takes an exclusive lock on `file`, returning whether it got it, which it
+// always does if `wait`.
+#[cfg(unix)]
+fn lock(file: &File, wait: bool) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let operation = if wait { ::libc::LOCK_EX } else { ::libc::LOCK_EX | ::libc::LOCK_NB };
+    loop {
+        if unsafe { ::libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(::libc::EINTR) => continue,
+            Some(::libc::EWOULDBLOCK) if !wait => return Ok(false),
+            _ => return Err(err),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File, _wait: bool) -> io::Result<bool> {
+    Ok(true)
+}
+
+static CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+static DOWNLOADED_BYTES: AtomicU64 = AtomicU64::new(0);
+static DOWNLOAD_NANOS: AtomicU64 = AtomicU64::new(0);
+static EXTRACT_NANOS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// How long this thread has spent waiting for downloads, so that
+    /// extracting from a download can leave that out.
+    static READ_TIME: Cell<Duration> = const { Cell::new(Duration::from_secs(0)) };
+}
+static FALLBACK_DOWNLOADS: Mutex<Vec<DownloadedFrom>> = Mutex::new(Vec::new());
+
+/// The URL a module of a sysroot was downloaded from.
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadedFrom {
+    pub module: String,
+    pub url: String,
+    /// Whether it's a fallback rather than the first URL tried, e.g. the CI
+    /// bucket after the mirrors, which may serve an unexpected artifact.
+    pub fallback: bool,
+}
+
+/// How the module archives this process needed were obtained, to help
+/// diagnose slow bisections.
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadStats {
+    /// Archives used from the cache directory.
+    pub cache_hits: usize,
+    /// Archives downloaded, including prefetched ones.
+    pub downloads: usize,
+    /// Bytes downloaded, including those of failed downloads.
+    pub downloaded_bytes: u64,
+    /// Time spent waiting for the network while downloading, and spent
+    /// extracting archives apart from that. These add up across the threads
+    /// modules are fetched on, so they can be more than the time it took.
+    pub download_time: Duration,
+    pub extract_time: Duration,
+}
+
+/// Returns the modules this process downloaded from a fallback URL so far.
+pub fn fallback_downloads() -> Vec<DownloadedFrom> {
+    FALLBACK_DOWNLOADS.lock().unwrap().clone()
+}
+
+/// Returns the download statistics of this process so far.
+pub fn download_stats() -> DownloadStats {
+    DownloadStats {
+        cache_hits: CACHE_HITS.load(Ordering::SeqCst),
+        downloads: DOWNLOADS.load(Ordering::SeqCst),
+        downloaded_bytes: DOWNLOADED_BYTES.load(Ordering::SeqCst),
+        download_time: Duration::from_nanos(DOWNLOAD_NANOS.load(Ordering::SeqCst)),
+        extract_time: Duration::from_nanos(EXTRACT_NANOS.load(Ordering::SeqCst)),
+    }
+}
 
 pub struct Sysroot {
     pub sha: String,
+    /// The commit this sysroot was installed for.
+    pub commit: Commit,
     pub rustc: PathBuf,
     pub rustdoc: PathBuf,
-    pub cargo: PathBuf,
+    /// `None` if cargo isn't one of `Config::components`.
+    pub cargo: Option<PathBuf>,
     pub triple: String,
+    pub cache_dir: PathBuf,
     pub preserve: bool,
+    /// The commit cargo was installed from.
+    pub cargo_sha: String,
+    /// Whether that isn't `sha`, e.g. because its cargo is known to be broken.
     pub used_fallback_cargo: bool,
+    /// The commit rust-std was installed from, see `Config::std_sha`.
+    pub std_sha: String,
     pub is_saving_sysroot: bool,
+    /// A directory for the commands run with this sysroot to write their
+    /// artifacts to, exported to them as `BISECT_OUTPUT_DIR`. It's up to
+    /// whoever sets it to create it, and it's left alone when the sysroot is
+    /// removed.
+    pub output_dir: Option<PathBuf>,
+    /// See `Config::pass_env`.
+    pub pass_env: Vec<String>,
+    /// Those of `Config::env_overrides` which apply to this commit.
+    pub env_overrides: Vec<EnvOverride>,
+    /// See `Config::rustc_flags`.
+    pub rustc_flags: Vec<String>,
+    /// Where the modules that weren't in the cache were downloaded from.
+    pub downloaded_from: Vec<DownloadedFrom>,
+    // Only held for their `Drop`; the lock is released after `Sysroot`'s
+    // own `Drop` removed the sysroot.
+    _interrupt_cleanup: InterruptCleanup,
+    _lock: SysrootLock,
 }
 
 impl Sysroot {
@@ -38,118 +432,410 @@ impl Sysroot {
         command
             .env_clear()
             .env("PATH", env::var("PATH").unwrap_or_default())
-            .env("CARGO", &self.cargo)
-            .env("CARGO_RELATIVE", &self.cargo.strip_prefix(&env::current_dir().unwrap()).unwrap())
+            .envs(self.pass_env.iter().filter_map(|var| env::var_os(var).map(|value| (var, value))))
             .env("RUSTC", &self.rustc)
-            .env("RUSTC_RELATIVE", &self.rustc.strip_prefix(&env::current_dir().unwrap()).unwrap())
             .env("RUSTDOC", &self.rustdoc)
-            .env("RUSTDOC_RELATIVE", &self.rustdoc.strip_prefix(&env::current_dir().unwrap()).unwrap());
+            .env("BISECT_COMMIT", &self.sha)
+            .env("BISECT_COMMIT_DATE", self.commit.date.to_rfc3339())
+            .env("BISECT_COMMIT_SUMMARY", &self.commit.summary);
+        if let Some(ref cargo) = self.cargo {
+            command.env("CARGO", cargo);
+        }
+        if let Some(ref output_dir) = self.output_dir {
+            command.env("BISECT_OUTPUT_DIR", output_dir);
+        }
+        for over in &self.env_overrides {
+            command.envs(&over.env);
+        }
+        let rustflags = self.rustflags();
+        if !rustflags.is_empty() {
+            // Added to the RUSTFLAGS the command would get otherwise.
+            let base = self.env_overrides.iter().rev().filter_map(|over| over.env.get("RUSTFLAGS").cloned()).next()
+                .or_else(|| if self.pass_env.iter().any(|var| var == "RUSTFLAGS") {
+                    env::var("RUSTFLAGS").ok()
+                } else {
+                    None
+                });
+            let flags = base.into_iter().filter(|flags| !flags.trim().is_empty()).chain(rustflags).collect::<Vec<_>>();
+            command.env("RUSTFLAGS", flags.join(" "));
+        }
+        // The relative paths only exist if the cache is below the current
+        // directory, which isn't the case for a cache dir elsewhere on disk.
+        // The tool paths are canonicalized, which on Windows gives them a
+        // `\\?\` prefix, so the current directory has to be as well.
+        let current_dir = env::current_dir().and_then(|dir| dir.canonicalize()).unwrap_or_default();
+        for &(var, path) in &[("CARGO_RELATIVE", self.cargo.as_ref()),
+                              ("RUSTC_RELATIVE", Some(&self.rustc)),
+                              ("RUSTDOC_RELATIVE", Some(&self.rustdoc))] {
+            if let Some(relative) = path.and_then(|path| path.strip_prefix(&current_dir).ok()) {
+                command.env(var, relative);
+            }
+        }
         command
     }
 
-    pub fn with_local_rustc(commit: &Commit, rustc: &str, triple: &str, preserve: bool, is_saving_sysroot: bool) -> Result<Self> {
-        let sha: &str = &commit.sha;
-        let unpack_into = format!("cache");
-        let mut used_fallback_cargo = false;
-
-        let cargo_sha = if commit.date < Utc.ymd(2017, 3, 20).and_hms(0, 0, 0) {
-            // Versions of rustc older than Mar 20 have bugs in
-            // their cargo. Use a known-good cargo for older rustcs
-            // instead.
-            used_fallback_cargo = true;
-            // get master commit for known-good cargo
-            ::get_commits(::EPOCH_COMMIT, "master")?.pop().unwrap().sha
-        } else {
-            sha.to_string()
-        };
+    /// A `command` running this sysroot's rustc, with `rustflags` passed as
+    /// arguments since rustc doesn't read RUSTFLAGS itself.
+    pub fn rustc_command(&self) -> Command {
+        let mut command = self.command(&self.rustc);
+        command.args(self.rustflags());
+        command
+    }
 
-        fs::create_dir_all(&unpack_into)?;
+    /// Like `rustc_command`, but running this sysroot's rustdoc.
+    pub fn rustdoc_command(&self) -> Command {
+        let mut command = self.command(&self.rustdoc);
+        command.args(self.rustflags());
+        command
+    }
 
-        let download = SysrootDownload {
-            directory: unpack_into.into(),
-            save_download: preserve,
-            rust_sha: sha.to_string(),
-            cargo_sha: cargo_sha,
-            triple: triple.to_string(),
-        };
+    /// A `command` running this sysroot's cargo, or `None` if cargo isn't
+    /// one of `Config::components`. It gets the flags in RUSTFLAGS.
+    pub fn cargo_command(&self) -> Option<Command> {
+        self.cargo.as_ref().map(|cargo| self.command(cargo))
+    }
+
+    /// The flags to compile with: `Config::rustc_flags`, followed by those
+    /// of the `env_overrides`. `command` adds them to RUSTFLAGS, which only
+    /// cargo reads, so `rustc_command` and `rustdoc_command` pass them as
+    /// arguments instead.
+    pub fn rustflags(&self) -> Vec<String> {
+        self.rustc_flags.iter()
+            .chain(self.env_overrides.iter().flat_map(|over| &over.rustflags))
+            .cloned()
+            .collect()
+    }
 
-        download.get_and_extract("cargo")?;
+    pub fn with_local_rustc(commit: &Commit, rustc: &str, triple: &str, preserve: bool, is_saving_sysroot: bool,
+                            config: &Config) -> Result<Self> {
+        let download = SysrootDownload::new(commit, triple, preserve, config)?;
+        let lock = SysrootLock::acquire(&download.directory, &download.rust_sha)?;
+        let interrupt_cleanup = download.interrupt_cleanup(is_saving_sysroot);
+        let mut downloaded_from = Vec::new();
+        if download.has_cargo() {
+            downloaded_from.extend(download.get_and_extract("cargo")?);
+        }
 
+        LIVE_SYSROOTS.lock().unwrap().extend(vec![download.rust_sha.clone(), download.cargo_sha.clone()]);
         Ok(Sysroot {
             rustc: PathBuf::from(rustc).canonicalize()
                 .chain_err(|| format!("failed to canonicalize rustc path: {}", rustc))?,
             rustdoc: PathBuf::from(rustc).canonicalize()
                 .chain_err(|| format!("failed to canonicalize rustc path: {}", rustc))?
-                .parent().unwrap().join("rustdoc"),
-            cargo: download.directory.join(&download.rust_sha).join("cargo/bin/cargo").canonicalize()
-                .chain_err(|| format!("failed to canonicalize cargo path for {}", download.cargo_sha))?,
-            sha: download.rust_sha,
+                .parent().unwrap().join(exe("rustdoc")),
+            cargo: download.cargo_path()?,
+            sha: download.rust_sha.clone(),
+            commit: download.commit,
+            cache_dir: download.directory,
             preserve: download.save_download,
             triple: download.triple,
-            used_fallback_cargo,
+            cargo_sha: download.cargo_sha,
+            // The local rustc comes with its own std.
+            std_sha: download.rust_sha,
+            used_fallback_cargo: download.used_fallback_cargo,
             is_saving_sysroot,
+            output_dir: None,
+            pass_env: download.pass_env.clone(),
+            env_overrides: download.env_overrides.clone(),
+            rustc_flags: download.rustc_flags.clone(),
+            downloaded_from,
+            _interrupt_cleanup: interrupt_cleanup,
+            _lock: lock,
         })
     }
 
-    pub fn install(commit: &Commit, triple: &str, preserve: bool, is_saving_sysroot: bool) -> Result<Self> {
-        let sha: &str = &commit.sha;
-        let unpack_into = format!("cache");
-        let mut used_fallback_cargo = false;
-
-        let cargo_sha = if commit.date < Utc.ymd(2017, 3, 20).and_hms(0, 0, 0) {
-            // Versions of rustc older than Mar 20 have bugs in
-            // their cargo. Use a known-good cargo for older rustcs
-            // instead.
-            used_fallback_cargo = true;
-            // get master commit for known-good cargo
-            ::get_commits(::EPOCH_COMMIT, "master")?.pop().unwrap().sha
-        } else {
-            sha.to_string()
+    /// Downloads and extracts the sysroot for `commit` into the cache directory,
+    /// fetching up to `config.jobs` modules concurrently.
+    pub fn install(commit: &Commit, triple: &str, preserve: bool, is_saving_sysroot: bool,
+                   config: &Config) -> Result<Self> {
+        #[cfg_attr(not(feature = "async-download"), allow(unused_mut))]
+        let mut download = SysrootDownload::new(commit, triple, preserve, config)?;
+        let lock = SysrootLock::acquire(&config.cache_dir, &download.rust_sha)?;
+        if let Some(dir) = download.predownloaded() {
+            info!("using the sysroot of {} downloaded ahead of time into {}", download.rust_sha, dir.display());
+            let interrupt_cleanup = download.interrupt_cleanup(is_saving_sysroot);
+            return download.into_predownloaded(&dir, is_saving_sysroot, interrupt_cleanup, lock);
+        }
+        if let Some(max_cache_size) = config.max_cache_size {
+            let mut in_use = LIVE_SYSROOTS.lock().unwrap().clone();
+            in_use.push(download.rust_sha.clone());
+            in_use.push(download.cargo_sha.clone());
+            cache::evict(&config.cache_dir, max_cache_size, &in_use)?;
+        }
+        let interrupt_cleanup = download.interrupt_cleanup(is_saving_sysroot);
+
+        let sysroot_dir = download.directory.join(&download.rust_sha);
+        let mut extracted = match download.extracted_modules() {
+            Some(extracted) => extracted,
+            // The marker is from another `EXTRACTION_FORMAT`, so whatever was
+            // extracted may be subtly wrong for this version.
+            None => {
+                warn!("removing sysroot {}, which was extracted by an incompatible version of the bisector",
+                    sysroot_dir.display());
+                fs::remove_dir_all(&sysroot_dir)?;
+                Vec::new()
+            }
         };
+        // A sysroot without the marker was only partially extracted, e.g.
+        // because the disk filled up, and can't be trusted at all.
+        if extracted.is_empty() && sysroot_dir.exists() {
+            warn!("removing partially extracted sysroot {}", sysroot_dir.display());
+            fs::remove_dir_all(&sysroot_dir)?;
+        }
+        // Extracting another commit's std over this one would leave a mix of
+        // both, so the sysroot has to start over.
+        let std_name = download.extracted_name("rust-std");
+        let plain_std_name = format!("rust-std-{}", triple);
+        if extracted.iter().any(|m| (*m == plain_std_name || m.starts_with(&format!("{}@", plain_std_name)))
+                                    && *m != std_name) {
+            warn!("removing sysroot {}, which has rust-std from another commit", sysroot_dir.display());
+            fs::remove_dir_all(&sysroot_dir)?;
+            extracted.clear();
+        }
+        let modules = config.components.iter()
+            .map(|c| &c[..])
+            .filter(|module| *module == "cargo" || !extracted.contains(&download.extracted_name(module)))
+            .collect::<Vec<_>>();
+        if modules.len() < config.components.len() {
+            info!("reusing the modules of {} already extracted into {}", download.rust_sha, sysroot_dir.display());
+        }
+        if config.offline {
+            download.check_offline(&modules)?;
+        }
+        #[cfg(feature = "async-download")]
+        download.fetch_async(&modules, config.jobs)?;
+        // Only returns once every module is extracted, including rustc, and
+        // the std dylibs are linked to its copies.
+        let downloaded_from = download.get_modules(&modules, config.jobs)?;
+        if config.components.iter().any(|c| c == "rust-std") {
+            download.check_std()?;
+        }
+        extracted.extend(modules.iter().filter(|m| **m != "cargo").map(|m| download.extracted_name(m)));
+        download.mark_extracted(&extracted)?;
+        cache::touch(&sysroot_dir)?;
 
-        fs::create_dir_all(&unpack_into)?;
+        let mut sysroot = download.into_sysroot(is_saving_sysroot, interrupt_cleanup, lock)?;
+        sysroot.downloaded_from = downloaded_from;
+        Ok(sysroot)
+    }
 
-        let download = SysrootDownload {
-            directory: unpack_into.into(),
-            save_download: preserve,
-            rust_sha: sha.to_string(),
-            cargo_sha: cargo_sha,
-            triple: triple.to_string(),
-        };
+    /// Checks that the sysroot works by compiling and running an empty
+    /// program with it, which catches e.g. a partially extracted std before
+    /// it shows up as a confusing failure of the actual test.
+    pub fn verify(&self) -> Result<()> {
+        let dir = self.cache_dir.join(&self.sha).join("verify");
+        fs::create_dir_all(&dir)?;
+        let result = self.compile_and_run(&dir);
+        let _ = fs::remove_dir_all(&dir);
+        result.chain_err(|| format!("sysroot for {} is broken", self.sha))
+    }
 
-        download.get_and_extract("rustc")?;
-        download.get_and_extract("rust-std")?;
-        download.get_and_extract("cargo")?;
+    fn compile_and_run(&self, dir: &Path) -> Result<()> {
+        let (src, bin) = (dir.join("main.rs"), dir.join(exe("main")));
+        fs::write(&src, "fn main() {}\n")?;
+        let output = self.command(&self.rustc).arg(&src).arg("-o").arg(&bin).current_dir(dir).output()
+            .chain_err(|| format!("failed to run {}", self.rustc.display()))?;
+        if !output.status.success() {
+            bail!("compiling an empty program failed: {}\n{}", output.status,
+                String::from_utf8_lossy(&output.stderr));
+        }
+        let status = self.command(&bin).status()
+            .chain_err(|| format!("failed to run {}", bin.display()))?;
+        if !status.success() {
+            bail!("running an empty program failed: {}", status);
+        }
+        Ok(())
+    }
+
+    /// Checks whether CI artifacts exist for `commit`, either in the cache
+    /// directory or at one of the URLs they're downloaded from, without
+    /// downloading them. Only rustc is looked for, as it's always installed
+    /// and the other modules are uploaded along with it.
+    pub fn has_artifacts(commit: &Commit, triple: &str, config: &Config) -> Result<bool> {
+        // Without cargo, there's no known-good one to look up either.
+        let config = Config { components: vec!["rustc".to_string()], ..config.clone() };
+        let download = SysrootDownload::new(commit, triple, false, &config)?;
+        let module = download.module("rustc");
+        if module.is_saved() || download.predownloaded().is_some() {
+            return Ok(true);
+        }
+        if config.offline {
+            return Ok(false);
+        }
+        module.is_uploaded()
+    }
 
-        download.into_sysroot(used_fallback_cargo, is_saving_sysroot)
+    /// The modules of `Config::components` which have no artifacts for
+    /// `commit`, looked for like in `has_artifacts` but for each of them.
+    pub fn missing_modules(commit: &Commit, triple: &str, config: &Config) -> Result<Vec<String>> {
+        let download = SysrootDownload::new(commit, triple, false, config)?;
+        if download.predownloaded().is_some() {
+            return Ok(Vec::new());
+        }
+        let mut missing = Vec::new();
+        for name in &config.components {
+            if name == "cargo" && download.cargo_dir().exists() {
+                continue;
+            }
+            let module = download.module(name);
+            if !module.is_saved() && (config.offline || !module.is_uploaded()?) {
+                missing.push(name.clone());
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Downloads the archives of the sysroot for `commit` into the cache
+    /// directory without extracting them, so that a later `install` with
+    /// `preserve` set finds them there.
+    pub fn prefetch(commit: &Commit, triple: &str, config: &Config) -> Result<()> {
+        let download = SysrootDownload::new(commit, triple, true, config)?;
+        for module in &config.components {
+            if module == "cargo" && download.cargo_dir().exists() {
+                continue;
+            }
+            download.module(module).prefetch()?;
+        }
+        Ok(())
     }
 }
 
 impl Drop for Sysroot {
     fn drop(&mut self) {
+        let cargo_in_use = {
+            let mut live = LIVE_SYSROOTS.lock().unwrap();
+            for sha in &[&self.sha, &self.cargo_sha] {
+                if let Some(i) = live.iter().position(|live| live == *sha) {
+                    live.remove(i);
+                }
+            }
+            live.contains(&self.cargo_sha)
+        };
+
         if !self.is_saving_sysroot {
-            fs::remove_dir_all(format!("cache/{}", self.sha)).unwrap_or_else(|err| {
-                info!("failed to remove {:?}, please do so manually: {:?}",
-                    format!("cache/{}", self.sha), err);
-            });
+            let mut paths = vec![self.cache_dir.join(&self.sha)];
+            // A fallback cargo is likely to be used again by the next sysroot,
+            // so it's left for the cache eviction to clean up; the commit's own
+            // cargo isn't.
+            if self.cargo_sha == self.sha && !cargo_in_use {
+                paths.push(cargo_dir(&self.cache_dir, &self.cargo_sha));
+            }
+            for path in paths.into_iter().filter(|path| path.exists()) {
+                fs::remove_dir_all(&path).unwrap_or_else(|err| {
+                    info!("failed to remove {:?}, please do so manually: {:?}", path, err);
+                });
+            }
         }
     }
 }
 
 #[derive(Debug, Clone)]
 struct SysrootDownload {
+    commit: Commit,
     directory: PathBuf,
     save_download: bool,
     rust_sha: String,
     cargo_sha: String,
+    std_sha: String,
     triple: String,
+    sysroot_dir: Option<PathBuf>,
+    retries: u32,
+    try_build: bool,
+    channel: Channel,
+    mirrors: Vec<String>,
+    offline: bool,
+    components: Vec<String>,
+    progress: bool,
+    free_space_factor: u64,
+    pass_env: Vec<String>,
+    env_overrides: Vec<EnvOverride>,
+    rustc_flags: Vec<String>,
+    used_fallback_cargo: bool,
+    client: Client,
+    auth: Option<ArtifactAuth>,
+    /// The archives `fetch_async` downloaded for this sysroot, which
+    /// `Module::get` extracts as they are.
+    fetched: Vec<(PathBuf, DownloadedFrom)>,
+}
+
+/// A bearer token to send along with requests for artifacts on `hosts`.
+#[derive(Clone)]
+struct ArtifactAuth {
+    token: String,
+    hosts: Vec<String>,
+}
+
+impl ArtifactAuth {
+    /// Reads the token from `RUSTC_ARTIFACT_TOKEN`. It's only sent to the
+    /// hosts listed in `RUSTC_ARTIFACT_AUTH_HOSTS`, or if that isn't set, to
+    /// the hosts of `mirrors`; never to the public CI bucket by default.
+    fn from_env(mirrors: &[String]) -> Option<Self> {
+        let token = env::var("RUSTC_ARTIFACT_TOKEN").ok()?;
+        let hosts: Vec<_> = match env::var("RUSTC_ARTIFACT_AUTH_HOSTS") {
+            Ok(hosts) => hosts.split(',').map(str::trim).filter(|h| !h.is_empty()).map(String::from).collect(),
+            Err(_) => mirrors.iter().filter_map(|mirror| url_host(mirror)).collect(),
+        };
+        if hosts.is_empty() {
+            warn!("RUSTC_ARTIFACT_TOKEN is set, but there are no hosts to send it to; \
+                   set RUSTC_ARTIFACT_AUTH_HOSTS or use --mirror");
+        }
+        Some(ArtifactAuth { token, hosts })
+    }
+
+    fn applies_to(&self, url: &str) -> bool {
+        url_host(url).is_some_and(|host| self.hosts.contains(&host))
+    }
+}
+
+// Keeps the token out of debug logs.
+impl fmt::Debug for ArtifactAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ArtifactAuth").field("hosts", &self.hosts).finish()
+    }
+}
+
+fn url_host(url: &str) -> Option<String> {
+    Url::parse(url).ok().and_then(|url| url.host_str().map(String::from))
+}
+
+/// How long a request may wait to connect or for the next part of the
+/// response before it fails.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The client all downloads go through, so that connections (and their TLS
+/// sessions) are reused across modules, retries and sysroots.
+fn client() -> Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static("rust-lang/bisect-rust"));
+        Client::builder()
+            .timeout(DOWNLOAD_TIMEOUT)
+            .default_headers(headers)
+            .build()
+            .expect("failed to create the HTTP client")
+    }).clone()
 }
 
+const ARTIFACTS_URL: &str = "https://s3-us-west-1.amazonaws.com/rust-lang-ci2";
+
 const MODULE_URLS: &[&str] = &[
-    "https://s3-us-west-1.amazonaws.com/rust-lang-ci2/rustc-builds/@SHA@/@MODULE@-nightly-@TRIPLE@.tar.xz",
-    "https://s3-us-west-1.amazonaws.com/rust-lang-ci2/rustc-builds-try/@SHA@/@MODULE@-nightly-@TRIPLE@.tar.xz",
+    "@BASE@/rustc-builds/@SHA@/@MODULE@-@CHANNEL@-@TRIPLE@.tar.xz",
+    "@BASE@/rustc-builds-try/@SHA@/@MODULE@-@CHANNEL@-@TRIPLE@.tar.xz",
 ];
 
+/// The URL templates to download modules from when using `mirror`.
+///
+/// A mirror containing `@SHA@` is taken as a complete template itself;
+/// anything else is a base URL laid out like the CI bucket.
+fn mirror_urls(mirror: &str) -> Vec<String> {
+    if mirror.contains("@SHA@") {
+        vec![mirror.to_string()]
+    } else {
+        MODULE_URLS.iter().map(|url| url.replace("@BASE@", mirror.trim_end_matches('/'))).collect()
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum ModuleVariant {
     Cargo,
@@ -177,150 +863,1243 @@ impl<'a> Module<'a> {
     fn sha(&self) -> &str {
         match self.variant {
             ModuleVariant::Cargo => &self.sysroot.cargo_sha,
-            _ => &self.sysroot.rust_sha,
+            ModuleVariant::Std => &self.sysroot.std_sha,
+            ModuleVariant::Rustc => &self.sysroot.rust_sha,
         }
     }
 
     fn urls(&self) -> Vec<String> {
-        MODULE_URLS.iter().map(|url| {
+        let mut templates = self.sysroot.mirrors.iter()
+            .flat_map(|mirror| mirror_urls(mirror))
+            .collect::<Vec<_>>();
+        templates.extend(mirror_urls(ARTIFACTS_URL));
+        let mut urls = templates.iter().map(|url| {
             url.replace("@MODULE@", &self.variant.to_string())
                .replace("@SHA@", self.sha())
+               .replace("@CHANNEL@", &self.sysroot.channel.to_string())
                .replace("@TRIPLE@", &self.sysroot.triple)
-        }).collect()
-    }
-
-    fn decompress<'b, R: BufRead + 'b>(&self, reader: R, extension: &str) -> Result<Box<Read + 'b>> {
-        if extension == "gz" {
-            Ok(Box::new(GzDecoder::new(reader)?))
-        } else if extension == "xz" {
-            Ok(Box::new(XzDecoder::new(reader)))
-        } else {
-            bail!("unknown extension {}", extension);
+        }).collect::<Vec<_>>();
+        if self.sysroot.try_build {
+            urls.sort_by_key(|url| !url.contains("/rustc-builds-try/"));
         }
+        urls
     }
 
-    fn get(&self) -> Result<()> {
-        let archive_path = |extension| {
-            self.sysroot.directory.join(format!("{}-{}-{}.tar.{}",
-                self.sha(), self.sysroot.triple, self.variant, extension))
-        };
-        for &extension in &["xz", "gz"] {
-            let archive_path = archive_path(extension);
+    /// Requests `url`, retrying with exponential backoff on errors which are
+    /// likely to be transient, i.e. timeouts, connection errors and 5xx
+    /// responses. Any other response (e.g. a 404 for a missing artifact) is
+    /// returned as is.
+    fn request(&self, url: &str) -> Result<reqwest::Response> {
+        self.send(Method::GET, url, HeaderMap::new())
+    }
 
-            let reader = if archive_path.exists() {
-                BufReader::new(File::open(&archive_path)?)
-            } else {
-                continue;
+    /// Like `request`, with any HTTP `method` and extra `headers`.
+    fn send(&self, method: Method, url: &str, headers: HeaderMap) -> Result<reqwest::Response> {
+        let mut delay = Duration::from_secs(1);
+        let mut attempt = 0;
+        loop {
+            debug!("requesting: {}", url);
+            let mut req = self.sysroot.client.request(method.clone(), url).headers(headers.clone());
+            if let Some(ref auth) = self.sysroot.auth {
+                if auth.applies_to(url) {
+                    req = req.header(AUTHORIZATION, format!("Bearer {}", auth.token));
+                }
+            }
+            let result = req.send();
+            let reason = match result {
+                Ok(ref resp) if !resp.status().is_server_error() => None,
+                Ok(ref resp) => Some(resp.status().to_string()),
+                Err(ref err) => Some(err.to_string()),
             };
-            match self.decompress(reader, extension)
-                .and_then(|reader| self.sysroot.extract(self, reader)) {
-                Ok(()) => return Ok(()),
-                Err(err) => {
-                    warn!("extracting {} failed: {:?}", archive_path.display(), err);
-                    fs::remove_file(archive_path)?;
-                    continue;
+            match reason {
+                Some(ref reason) if attempt < self.sysroot.retries => {
+                    attempt += 1;
+                    warn!("requesting {} failed: {}; retrying in {}s (retry {} of {})",
+                        url, reason, delay.as_secs(), attempt, self.sysroot.retries);
+                    thread::sleep(delay);
+                    delay *= 2;
                 }
+                _ => return Ok(result?),
             }
         }
+    }
+
+    /// Fetches the SHA-256 checksum CI publishes alongside the archive at `url`,
+    /// if there is one.
+    fn expected_checksum(&self, url: &str) -> Result<Option<String>> {
+        let url = format!("{}.sha256", url);
+        let mut resp = self.request(&url)?;
+        if !resp.status().is_success() {
+            return Ok(None);
+        }
+        // The file looks like `<hex digest>  <file name>`.
+        let body = resp.text()?;
+        Ok(body.split_whitespace().next().map(|digest| digest.to_lowercase()))
+    }
+
+    fn archive_path(&self, extension: &str) -> PathBuf {
+        self.sysroot.directory.join(format!("{}-{}-{}.tar.{}",
+            self.sha(), self.sysroot.triple, self.variant, extension))
+    }
+
+    fn is_saved(&self) -> bool {
+        ARCHIVE_EXTENSIONS.iter().any(|extension| self.archive_path(extension).exists())
+    }
 
+    // Whether any of the URLs the archive is downloaded from has it, asked
+    // with HEAD requests so nothing is downloaded.
+    fn is_uploaded(&self) -> Result<bool> {
         for url in self.urls() {
-            let extension = if url.ends_with("gz") { "gz" } else { "xz" };
+            if self.send(Method::HEAD, &url, HeaderMap::new())?.status().is_success() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
 
-            debug!("requesting: {}", url);
-            let resp = reqwest::get(&url)?;
-            debug!("{}", resp.status());
-            let mut reader = if resp.status().is_success() {
-                BufReader::new(resp)
-            } else {
-                continue;
-            };
-            let archive_path = archive_path(extension);
+    /// Checks whether a valid archive is saved at `archive_path`. Cached
+    /// archives are only trusted if they still match the checksum they were
+    /// saved with; otherwise they're likely truncated or corrupted, and get
+    /// removed.
+    fn is_cached(&self, archive_path: &Path) -> Result<bool> {
+        if !archive_path.exists() {
+            return Ok(false);
+        }
+        let checksum_path = checksum_path(archive_path);
+        let expected = fs::read_to_string(&checksum_path).ok();
+        let actual = sha256_file(archive_path)?;
+        if expected.as_ref().map(|s| s.trim()) != Some(&actual[..]) {
+            warn!("cached archive {} does not match its checksum, fetching it again",
+                archive_path.display());
+            fs::remove_file(archive_path)?;
+            let _ = fs::remove_file(&checksum_path);
+            return Ok(false);
+        }
+        Ok(true)
+    }
 
-            let reader: Box<BufRead> = if self.sysroot.save_download && !archive_path.exists() {
-                let mut file = File::create(&archive_path)?;
-                io::copy(&mut reader, &mut file)?;
-                Box::new(BufReader::new(File::open(&archive_path)?))
+    /// Starts downloading the archive at `url`, to be saved in the cache
+    /// directory if `save` is set. Returns `None` if there's no archive at
+    /// `url`.
+    ///
+    /// If an earlier download of the archive was interrupted, it's resumed
+    /// where it left off, as long as the archive didn't change since.
+    fn start_download(&self, url: &str, save: bool) -> Result<Option<Download>> {
+        let mut resume = if save { self.claim_resumable(archive_extension(url)?) } else { None };
+        let mut headers = HeaderMap::new();
+        if let Some(ref resume) = resume {
+            // Only digits, so always a valid header value.
+            headers.insert(RANGE, HeaderValue::from_str(&format!("bytes={}-", resume.len)).unwrap());
+            headers.insert(IF_RANGE, resume.etag.clone());
+        }
+        let mut resp = self.send(Method::GET, url, headers)?;
+        debug!("{}", resp.status());
+        if let Some(partial) = resume.take() {
+            let start = resp.headers().get(CONTENT_RANGE).and_then(|range| range.to_str().ok())
+                .and_then(|range| range.trim_start_matches("bytes ").split('-').next())
+                .and_then(|start| start.parse::<u64>().ok());
+            if resp.status() == StatusCode::PARTIAL_CONTENT && start == Some(partial.len) {
+                info!("resuming the download of {} from {} MB", url, partial.len / 1_000_000);
+                resume = Some(partial);
             } else {
-                Box::new(reader)
-            };
-
-            match self.decompress(reader, extension)
-                .and_then(|reader| self.sysroot.extract(self, reader)) {
-                Ok(()) => return Ok(()),
-                Err(err) => {
-                    warn!("extracting {} failed: {:?}", url, err);
-                    if self.sysroot.save_download {
-                        fs::remove_file(archive_path)?;
-                    }
-                    continue;
+                fs::remove_file(&partial.path)?;
+                // The archive changed since, so the whole of it was sent
+                // instead; anything else, e.g. a range past its end, has to
+                // be requested again.
+                if resp.status() != StatusCode::OK {
+                    debug!("could not resume {}: {}, downloading it from the start", url, resp.status());
+                    resp = self.request(url)?;
                 }
             }
         }
+        if !resp.status().is_success() {
+            return Ok(None);
+        }
+        let extension = served_extension(&resp, archive_extension(url)?);
+        let expected = self.expected_checksum(url)?;
+        let mut replay = None;
+        let file = match resume {
+            Some(resume) if extension == archive_extension(url)? => {
+                let path = resume.take();
+                replay = Some(File::open(&path)?);
+                let file = fs::OpenOptions::new().append(true).open(&path)?;
+                Some((path, file))
+            }
+            _ if save => {
+                let partial_path = partial_path(&self.archive_path(extension));
+                let file = File::create(&partial_path)?;
+                Some((partial_path, file))
+            }
+            _ => None,
+        };
+        // Only a download whose archive can be told apart from a changed one
+        // later can be resumed.
+        let resumable = match resp.headers().get(ETAG).and_then(|etag| etag.to_str().ok()) {
+            Some(etag) if !etag.starts_with("W/") && (resp.status() == StatusCode::PARTIAL_CONTENT
+                || resp.headers().get(ACCEPT_RANGES).is_some_and(|ranges| ranges == "bytes")) => {
+                Some(resumable_path(&self.archive_path(extension), etag))
+            }
+            _ => None,
+        };
+        let progress = if self.sysroot.progress {
+            Some(Progress::new(url, resp.content_length()))
+        } else {
+            None
+        };
+        Ok(Some(Download {
+            url: url.to_string(),
+            resp,
+            extension,
+            file,
+            replay,
+            resumable,
+            complete: false,
+            hasher: Sha256::new(),
+            expected,
+            progress,
+        }))
+    }
 
-        bail!("unable to download sha {} triple {} module {}",
-            self.sha(), self.sysroot.triple, self.variant);
+    /// Takes over the archive left behind by an interrupted download (see
+    /// `Download`'s `Drop`) if there is one, by moving it to a partial path
+    /// of its own; if another process beats us to it, this starts over.
+    fn claim_resumable(&self, extension: &str) -> Option<Resume> {
+        let archive_path = self.archive_path(extension);
+        let prefix = format!("{}.", archive_path.file_name().unwrap().to_string_lossy());
+        for entry in fs::read_dir(&self.sysroot.directory).ok()?.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(&prefix) || !name.ends_with(".resume") {
+                continue;
+            }
+            let etag = match hex::decode(&name[prefix.len()..name.len() - ".resume".len()]).ok()
+                .and_then(|etag| HeaderValue::from_bytes(&etag).ok()) {
+                Some(etag) => etag,
+                None => continue,
+            };
+            let path = partial_path(&archive_path);
+            if fs::rename(entry.path(), &path).is_err() {
+                continue;
+            }
+            let len = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+            return Some(Resume { path, len, etag, from: entry.path() });
+        }
+        None
     }
-}
 
-impl SysrootDownload {
-    fn into_sysroot(self, used_fallback_cargo: bool, is_saving_sysroot: bool) -> Result<Sysroot> {
-        Ok(Sysroot {
-            rustc: self.directory.join(&self.rust_sha).join("rustc/bin/rustc").canonicalize()
-                .chain_err(|| format!("failed to canonicalize rustc path for {}", self.rust_sha))?,
-            rustdoc: self.directory.join(&self.rust_sha).join("rustc/bin/rustdoc").canonicalize()
-                .chain_err(|| format!("failed to canonicalize rustdoc path for {}", self.rust_sha))?,
-            cargo: self.directory.join(&self.rust_sha).join("cargo/bin/cargo").canonicalize()
-                .chain_err(|| format!("failed to canonicalize cargo path for {}", self.cargo_sha))?,
-            sha: self.rust_sha,
-            preserve: self.save_download,
-            triple: self.triple,
-            used_fallback_cargo,
-            is_saving_sysroot,
-        })
+    /// Downloads the archive at `url` into the cache directory and verifies
+    /// it against the published checksum. Returns `false` if there's no
+    /// archive at `url`.
+    fn download(&self, url: &str) -> Result<bool> {
+        match self.start_download(url, true)? {
+            Some(download) => {
+                if let Some(stored) = self.stored_archive(&download)? {
+                    self.save_stored(&stored, &download)?;
+                    CACHE_HITS.fetch_add(1, Ordering::SeqCst);
+                    return Ok(true);
+                }
+                let archive_path = self.archive_path(download.extension);
+                download.finish(&archive_path)?;
+                DOWNLOADS.fetch_add(1, Ordering::SeqCst);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
     }
 
-    fn get_module(&self, module: &str) -> Result<()> {
-        Module {
-            variant: match module {
-                "cargo" => ModuleVariant::Cargo,
-                "rustc" => ModuleVariant::Rustc,
-                "rust-std" => ModuleVariant::Std,
-                _ => panic!("unknown module variant: {}", module),
-            },
-            sysroot: self,
-        }.get()
+    /// Looks for an archive with the checksum published for `download` in the
+    /// store, i.e. one which was saved before under another name, so that it
+    /// needn't be downloaded again. Like cached archives, it's only trusted if
+    /// it still matches its checksum.
+    fn stored_archive(&self, download: &Download) -> Result<Option<PathBuf>> {
+        let digest = match download.expected {
+            Some(ref digest) => digest,
+            None => return Ok(None),
+        };
+        let stored = cache::stored_path(&self.sysroot.directory, digest);
+        if !stored.exists() {
+            return Ok(None);
+        }
+        if sha256_file(&stored)? != *digest {
+            warn!("stored archive {} does not match its checksum, removing it", stored.display());
+            fs::remove_file(&stored)?;
+            return Ok(None);
+        }
+        info!("{} for {} is already stored as {}, not downloading it again",
+            self.variant, self.sha(), stored.display());
+        Ok(Some(stored))
     }
 
-    fn get_and_extract(&self, module: &str) -> Result<()> {
-        self.get_module(module)
+    /// Saves the stored archive `stored` as this module's archive in place of
+    /// `download`.
+    fn save_stored(&self, stored: &Path, download: &Download) -> Result<()> {
+        let archive_path = self.archive_path(download.extension);
+        fs::write(checksum_path(&archive_path), download.expected.as_ref().unwrap())?;
+        link_stored(stored, &archive_path)?;
+        cache::touch(&archive_path)
     }
 
-    fn extract(&self, module: &Module, reader: Box<Read>) -> Result<()> {
+    /// Downloads and extracts this module into `unpack_into`, returning the
+    /// dylibs that still need to be linked by
+    /// `SysrootDownload::link_std_dylibs`, and where it was downloaded from
+    /// unless it was in the cache.
+    fn get(&self, unpack_into: &Path) -> Result<(Vec<PathBuf>, Option<DownloadedFrom>)> {
+        let module = self.variant.to_string();
+        let fetched = self.sysroot.fetched.iter().find(|(_, from)| from.module == module);
+        if let Some((archive_path, from)) = fetched {
+            // Verified and counted as a download as it was fetched, so it
+            // needn't be hashed again.
+            let reader = BufReader::new(File::open(archive_path)?);
+            let result = archive_extension(&archive_path.to_string_lossy())
+                .and_then(|extension| decompress(reader, extension))
+                .and_then(|reader| self.sysroot.extract(self, reader, unpack_into));
+            match result {
+                Ok(to_link) => {
+                    // Only kept if the download would have been, like a
+                    // streamed one; its stored copy goes along with it.
+                    if !self.sysroot.save_download {
+                        let _ = cache::remove(archive_path);
+                        cache::remove_unreferenced(&self.sysroot.directory)?;
+                    }
+                    return Ok((to_link, Some(from.clone())));
+                }
+                Err(err) => warn!("extracting {} failed: {:?}", archive_path.display(), err),
+            }
+        }
+
+        for &extension in ARCHIVE_EXTENSIONS {
+            let archive_path = self.archive_path(extension);
+            if !self.is_cached(&archive_path)? {
+                continue;
+            }
+            cache::touch(&archive_path)?;
+
+            let reader = BufReader::new(File::open(&archive_path)?);
+            match decompress(reader, extension)
+                .and_then(|reader| self.sysroot.extract(self, reader, unpack_into)) {
+                Ok(to_link) => {
+                    CACHE_HITS.fetch_add(1, Ordering::SeqCst);
+                    return Ok((to_link, None));
+                }
+                Err(err) => {
+                    warn!("extracting {} failed: {:?}", archive_path.display(), err);
+                    fs::remove_file(&archive_path)?;
+                    fs::remove_file(checksum_path(&archive_path))?;
+                    continue;
+                }
+            }
+        }
+
+        if self.sysroot.offline {
+            bail!("running offline, but there is no usable {} archive for {} in {}",
+                self.variant, self.sha(), self.sysroot.directory.display());
+        }
+
+        let mut any_archive = false;
+        for (i, url) in self.urls().into_iter().enumerate() {
+            let save = self.sysroot.save_download;
+            let mut download = match self.start_download(&url, save)? {
+                Some(download) => download,
+                None => continue,
+            };
+            any_archive = true;
+            let extension = download.extension;
+            let archive_path = self.archive_path(extension);
+
+            if let Some(stored) = self.stored_archive(&download)? {
+                if save {
+                    self.save_stored(&stored, &download)?;
+                }
+                drop(download);
+                let reader = BufReader::new(File::open(&stored)?);
+                match decompress(reader, extension)
+                    .and_then(|reader| self.sysroot.extract(self, reader, unpack_into)) {
+                    Ok(to_link) => {
+                        CACHE_HITS.fetch_add(1, Ordering::SeqCst);
+                        return Ok((to_link, None));
+                    }
+                    Err(err) => {
+                        warn!("extracting {} failed: {:?}", stored.display(), err);
+                        continue;
+                    }
+                }
+            }
+
+            check_free_space(&self.sysroot.directory, self.sysroot.free_space_factor, &download.url,
+                             download.resp.content_length())?;
+
+            // The archive is extracted as it comes in, so it can only be
+            // verified once it's complete. Until then it's kept apart in a
+            // staging directory, which is only moved into the sysroot if it
+            // matches, and removed otherwise, before the next URL is tried.
+            let staging = self.staging_dir(unpack_into);
+            if staging.exists() {
+                fs::remove_dir_all(&staging)?;
+            }
+            let result = decompress(BufReader::new(&mut download), extension)
+                .and_then(|reader| self.sysroot.extract(self, reader, &staging));
+            let result = result.and_then(|to_link| {
+                download.finish(&archive_path)?;
+                move_into(&staging, unpack_into)?;
+                Ok(to_link)
+            });
+            if staging.exists() {
+                fs::remove_dir_all(&staging)?;
+            }
+            match result {
+                Ok(to_link) => {
+                    DOWNLOADS.fetch_add(1, Ordering::SeqCst);
+                    if save {
+                        cache::touch(&archive_path)?;
+                    }
+                    info!("downloaded {} for {} from {}", self.variant, self.sha(), url);
+                    let from = DownloadedFrom {
+                        module: self.variant.to_string(),
+                        url,
+                        fallback: i > 0,
+                    };
+                    if from.fallback {
+                        FALLBACK_DOWNLOADS.lock().unwrap().push(from.clone());
+                    }
+                    return Ok((to_link, Some(from)));
+                }
+                Err(err) => {
+                    warn!("extracting {} failed: {:?}", url, err);
+                    continue;
+                }
+            }
+        }
+
+        if !any_archive {
+            bail!(ErrorKind::MissingArtifacts(self.sha().to_string(), self.variant.to_string()));
+        }
+        bail!("unable to download sha {} triple {} module {}",
+            self.sha(), self.sysroot.triple, self.variant);
+    }
+
+    /// Where an archive that's still being downloaded is extracted, before
+    /// it's moved to `unpack_into`. Next to it, so that it can be moved by
+    /// renaming.
+    fn staging_dir(&self, unpack_into: &Path) -> PathBuf {
+        let name = unpack_into.file_name().unwrap().to_string_lossy();
+        unpack_into.with_file_name(format!("{}.{}-{}.partial", name, self.variant, process::id()))
+    }
+
+    /// Downloads this module's archive into the cache directory without
+    /// extracting it, unless it's already there.
+    fn prefetch(&self) -> Result<()> {
+        for &extension in ARCHIVE_EXTENSIONS {
+            if self.is_cached(&self.archive_path(extension))? {
+                return Ok(());
+            }
+        }
+        for url in self.urls() {
+            if self.download(&url)? {
+                return Ok(());
+            }
+        }
+        bail!("unable to download sha {} triple {} module {}",
+            self.sha(), self.sysroot.triple, self.variant);
+    }
+}
+
+/// The compression formats archives may come in, in the order saved archives
+/// are looked for.
+const ARCHIVE_EXTENSIONS: &[&str] = &["zst", "xz", "gz", "bz2"];
+
+/// Decompresses an archive with the given extension, out of
+/// `ARCHIVE_EXTENSIONS`.
+fn decompress<'b, R: BufRead + 'b>(reader: R, extension: &str) -> Result<Box<dyn Read + 'b>> {
+    if extension == "gz" {
+        Ok(Box::new(GzDecoder::new(reader)?))
+    } else if extension == "xz" {
+        Ok(Box::new(XzDecoder::new_multi_decoder(reader)))
+    } else if extension == "zst" {
+        Ok(Box::new(ZstdDecoder::with_buffer(reader)?))
+    } else if extension == "bz2" {
+        Ok(Box::new(BzDecoder::new(reader)))
+    } else {
+        bail!("unknown extension {}", extension);
+    }
+}
+
+/// The extension of the archive at `url`, out of `ARCHIVE_EXTENSIONS`.
+fn archive_extension(url: &str) -> Result<&'static str> {
+    match ARCHIVE_EXTENSIONS.iter().find(|extension| url.ends_with(&format!(".tar.{}", extension))) {
+        Some(extension) => Ok(extension),
+        None => bail!("unknown archive extension in {}", url),
+    }
+}
+
+/// The extension of the archive actually served in `resp`, which needn't be
+/// the `requested` one, see `extension_served`.
+fn served_extension(resp: &reqwest::Response, requested: &'static str) -> &'static str {
+    let header = |name| resp.headers().get(name).and_then(|value| value.to_str().ok());
+    extension_served(header(CONTENT_TYPE), header(CONTENT_ENCODING), resp.url().as_str(), requested)
+}
+
+/// The extension of an archive served with the given content type and
+/// encoding from `final_url`, which needn't be the `requested` one: mirrors
+/// may redirect to a differently compressed archive, or serve one under the
+/// wrong name. A redirect to a URL with another extension is trusted first,
+/// then the content encoding and type.
+fn extension_served(content_type: Option<&str>, content_encoding: Option<&str>, final_url: &str,
+                    requested: &'static str) -> &'static str {
+    if let Ok(extension) = archive_extension(final_url) {
+        if extension != requested {
+            debug!("{} was redirected to a .tar.{} archive", final_url, extension);
+            return extension;
+        }
+    }
+    let from_header = |value: Option<&str>| {
+        let value = value.map(|value| value.split(';').next().unwrap_or("").trim().to_lowercase());
+        match value.as_ref().map(|value| &value[..]) {
+            Some("application/x-xz") | Some("application/xz") | Some("xz") => Some("xz"),
+            Some("application/gzip") | Some("application/x-gzip") | Some("gzip") => Some("gz"),
+            Some("application/zstd") | Some("application/x-zstd") | Some("zstd") => Some("zst"),
+            Some("application/x-bzip2") | Some("application/x-bzip") | Some("bzip2") => Some("bz2"),
+            _ => None,
+        }
+    };
+    match from_header(content_encoding).or_else(|| from_header(content_type)) {
+        Some(extension) => {
+            if extension != requested {
+                debug!("{} was served as a .tar.{} archive", final_url, extension);
+            }
+            extension
+        }
+        None => requested,
+    }
+}
+
+/// Where a file from the rust-std archive goes in the extracted sysroot, or
+/// `None` if it isn't needed there.
+///
+/// `path` is relative to the container directory of the archive. Everything
+/// below `rust-std-<triple>/lib/rustlib` is kept, whichever mix of rlibs,
+/// dylibs and self-contained objects the target ships.
+fn std_destination(path: &Path, triple: &str) -> Option<PathBuf> {
+    let std_prefix = format!("rust-std-{}/lib/rustlib", triple);
+    path.strip_prefix(&std_prefix).ok().map(|path| Path::new("rustc/lib/rustlib").join(path))
+}
+
+/// The container directory of an archive of `variant`, e.g.
+/// `rustc-nightly-x86_64-unknown-linux-gnu`, given the path of one of its
+/// entries. Everything in the archive is below this, though it may be nested
+/// further, e.g. in `dist/`.
+fn container_dir(path: &Path, variant: ModuleVariant) -> Option<PathBuf> {
+    let prefix = format!("{}-", variant);
+    let mut container = PathBuf::new();
+    for component in path.components() {
+        container.push(component);
+        if component.as_os_str().to_str().is_some_and(|name| name.starts_with(&prefix)) {
+            return Some(container);
+        }
+    }
+    None
+}
+
+/// Moves everything in the directory `from` into the directory `to`, merging
+/// it with any directories already there, e.g. rust-std's `rustc/lib` with
+/// rustc's. Files already in `to` are replaced. `from` is removed.
+///
+/// The modules of a sysroot are moved in from their own threads, so a
+/// directory may be moved into place by another module between looking for it
+/// and renaming ours over it; it's merged with then too.
+fn move_into(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let is_dir = entry.file_type()?.is_dir();
+        let dst = to.join(entry.file_name());
+        if is_dir && dst.is_dir() {
+            move_into(&entry.path(), &dst)?;
+        } else if let Err(err) = fs::rename(entry.path(), &dst) {
+            if !(is_dir && dst.is_dir()) {
+                return Err(err).chain_err(|| {
+                    format!("could not move {} to {}", entry.path().display(), dst.display())
+                });
+            }
+            move_into(&entry.path(), &dst)?;
+        }
+    }
+    fs::remove_dir(from)?;
+    Ok(())
+}
+
+/// The file name of the executable `name` on the host, e.g. `rustc.exe` on
+/// Windows.
+fn exe(name: &str) -> String {
+    format!("{}{}", name, env::consts::EXE_SUFFIX)
+}
+
+fn is_dylib(path: &Path) -> bool {
+    matches!(path.extension().and_then(|ext| ext.to_str()), Some("dylib") | Some("so") | Some("dll"))
+}
+
+/// An archive being downloaded. It's hashed as it's read, and written to a
+/// temporary file if it's to be saved, so that it can be saved, verified and
+/// extracted in a single pass.
+///
+/// If the download is resumed, what was downloaded before is read back from
+/// that file first, so that it's hashed and extracted like the rest.
+struct Download {
+    url: String,
+    resp: reqwest::Response,
+    // The compression of the archive, as served.
+    extension: &'static str,
+    file: Option<(PathBuf, File)>,
+    replay: Option<File>,
+    // Where to leave the partial file if the download is cut short, to be
+    // resumed by the next one, if the server allows that.
+    resumable: Option<PathBuf>,
+    complete: bool,
+    hasher: Sha256,
+    expected: Option<String>,
+    progress: Option<Progress>,
+}
+
+/// How often the progress of a download is reported.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The progress of a download, reported on stderr as a line every
+/// `PROGRESS_INTERVAL` rather than redrawn, since several downloads may be
+/// running at once.
+struct Progress {
+    name: String,
+    total: Option<u64>,
+    done: u64,
+    last_report: Instant,
+    finished: bool,
+}
+
+impl Progress {
+    fn new(url: &str, total: Option<u64>) -> Progress {
+        Progress {
+            name: url.rsplit('/').next().unwrap_or(url).to_string(),
+            total,
+            done: 0,
+            last_report: Instant::now(),
+            finished: false,
+        }
+    }
+
+    fn advance(&mut self, n: usize) {
+        if self.finished {
+            return;
+        }
+        self.done += n as u64;
+        self.finished = n == 0;
+        if !self.finished && self.last_report.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+        let mb = |bytes| bytes as f64 / 1_000_000.0;
+        match self.total {
+            Some(total) if total > 0 => eprintln!("{}: {:.1} of {:.1} MB ({}%)",
+                self.name, mb(self.done), mb(total), self.done * 100 / total),
+            _ => eprintln!("{}: {:.1} MB", self.name, mb(self.done)),
+        }
+    }
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(ref mut replay) = self.replay {
+            let n = replay.read(buf)?;
+            if n > 0 {
+                self.hasher.input(&buf[..n]);
+                return Ok(n);
+            }
+        }
+        self.replay = None;
+        let start = Instant::now();
+        let n = self.resp.read(buf)?;
+        self.complete = n == 0;
+        let elapsed = start.elapsed();
+        DOWNLOAD_NANOS.fetch_add(elapsed.as_nanos() as u64, Ordering::SeqCst);
+        READ_TIME.with(|time| time.set(time.get() + elapsed));
+        DOWNLOADED_BYTES.fetch_add(n as u64, Ordering::SeqCst);
+        if let Some(ref mut progress) = self.progress {
+            progress.advance(n);
+        }
+        if let Some((_, ref mut file)) = self.file {
+            file.write_all(&buf[..n])?;
+        }
+        self.hasher.input(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl Download {
+    /// Reads the rest of the archive and verifies it against the published
+    /// checksum. If it was saved, it's then moved into the store and linked
+    /// into place at `archive_path`; writing it to a temporary file until then
+    /// means that concurrent downloads of the same archive (e.g. when
+    /// prefetching) never see a partial file.
+    fn finish(mut self, archive_path: &Path) -> Result<()> {
+        io::copy(&mut self, &mut io::sink())?;
+        let actual = hex::encode(mem::replace(&mut self.hasher, Sha256::new()).result());
+        match self.expected {
+            Some(ref expected) if *expected != actual => {
+                bail!("checksum mismatch for {}: expected {}, got {}", self.url, expected, actual);
+            }
+            Some(_) => {}
+            None => warn!("no checksum published for {}, skipping verification", self.url),
+        }
+        if let Some((partial_path, file)) = self.file.take() {
+            file.sync_all()?;
+            drop(file);
+            fs::write(checksum_path(archive_path), &actual)?;
+            store_archive(&partial_path, archive_path, &actual)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Download {
+    /// Removes the partial file, unless the download was cut short and can
+    /// be resumed. A complete one failed to verify or extract, so it's of no
+    /// use.
+    fn drop(&mut self) {
+        if let Some((ref partial_path, _)) = self.file {
+            let has_data = fs::metadata(partial_path).map(|metadata| metadata.len() > 0).unwrap_or(false);
+            match self.resumable {
+                Some(ref path) if has_data && !self.complete && fs::rename(partial_path, path).is_ok() => {
+                    info!("keeping the partial download of {} to resume it later", self.url);
+                }
+                _ => {
+                    let _ = fs::remove_file(partial_path);
+                }
+            }
+        }
+    }
+}
+
+/// An archive partially downloaded by an earlier `Download`, moved to
+/// `path` from where that left it.
+struct Resume {
+    path: PathBuf,
+    len: u64,
+    // The ETag the archive had then, which it must still have for the
+    // download to be resumed.
+    etag: HeaderValue,
+    from: PathBuf,
+}
+
+impl Resume {
+    /// Hands the archive over to a `Download` resuming it.
+    fn take(mut self) -> PathBuf {
+        mem::replace(&mut self.path, PathBuf::new())
+    }
+}
+
+impl Drop for Resume {
+    /// Puts the archive back for a later download to resume, unless it was
+    /// taken over or removed, e.g. because this URL doesn't serve it.
+    fn drop(&mut self) {
+        if self.path.exists() {
+            let _ = fs::rename(&self.path, &self.from);
+        }
+    }
+}
+
+/// Where an interrupted download of `archive_path` is left to be resumed.
+/// The `etag` is part of the name, hex-encoded since it may contain any
+/// character.
+fn resumable_path(archive_path: &Path, etag: &str) -> PathBuf {
+    let mut path = archive_path.as_os_str().to_owned();
+    path.push(format!(".{}.resume", hex::encode(etag)));
+    PathBuf::from(path)
+}
+
+/// The file in an extracted sysroot which lists the modules that were
+/// completely extracted into it. It's written last, so a sysroot missing it
+/// was only partially extracted.
+const EXTRACTED_MARKER: &str = ".extracted";
+
+/// The version of how sysroots are extracted, recorded on the first line of
+/// the `EXTRACTED_MARKER`. Bump it whenever a change to the extraction would
+/// leave sysroots extracted before it subtly wrong, e.g. which std dylibs get
+/// linked, so that those are extracted again rather than reused.
+const EXTRACTION_FORMAT: u32 = 1;
+
+/// The directory the cargo of `cargo_sha` is shared from, see
+/// `SysrootDownload::get_cargo`.
+fn cargo_dir(cache_dir: &Path, cargo_sha: &str) -> PathBuf {
+    cache_dir.join("cargo").join(cargo_sha)
+}
+
+/// The path of the file recording the checksum of a saved archive.
+pub(crate) fn checksum_path(archive_path: &Path) -> PathBuf {
+    let mut path = archive_path.as_os_str().to_owned();
+    path.push(".sha256");
+    PathBuf::from(path)
+}
+
+/// Moves the complete and verified archive at `partial_path` into the store
+/// (see `cache::stored_path`), unless an identical one is stored already, and
+/// links it into place at `archive_path`. Its checksum file must be written
+/// first, since that's what keeps it from being removed from the store.
+pub(crate) fn store_archive(partial_path: &Path, archive_path: &Path, digest: &str) -> Result<()> {
+    let stored = cache::stored_path(archive_path.parent().unwrap(), digest);
+    fs::create_dir_all(stored.parent().unwrap())?;
+    if stored.exists() {
+        fs::remove_file(partial_path)?;
+    } else {
+        fs::rename(partial_path, &stored)?;
+    }
+    link_stored(&stored, archive_path)
+}
+
+/// Hard links the stored archive `stored` to `archive_path`, or copies it on
+/// file systems without hard links. Either way it's replaced atomically.
+fn link_stored(stored: &Path, archive_path: &Path) -> Result<()> {
+    let link = partial_path(archive_path);
+    if let Err(err) = fs::hard_link(stored, &link) {
+        debug!("linking {} failed ({}), copying it instead", stored.display(), err);
+        fs::copy(stored, &link)?;
+    }
+    if let Err(err) = fs::rename(&link, archive_path) {
+        let _ = fs::remove_file(&link);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// A unique path to download `archive_path` to before moving it into place.
+fn partial_path(archive_path: &Path) -> PathBuf {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let mut path = archive_path.as_os_str().to_owned();
+    path.push(format!(".{}-{}.part", process::id(), NEXT_ID.fetch_add(1, Ordering::SeqCst)));
+    PathBuf::from(path)
+}
+
+/// Computes the hex-encoded SHA-256 digest of the file at `path`.
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.result()))
+}
+
+/// A date as `(year, month, day)`.
+type Ymd = (i32, u32, u32);
+
+/// Ranges of commit dates, as `[start, end)`, in which cargo is known to be
+/// broken on master. Sysroots for these commits use a known-good cargo
+/// instead.
+const BROKEN_CARGO: &[(Ymd, Ymd)] = &[
+    // Versions of rustc older than Mar 20 2017 have bugs in their cargo.
+    ((2015, 1, 1), (2017, 3, 20)),
+];
+
+fn has_broken_cargo(commit: &Commit) -> bool {
+    BROKEN_CARGO.iter().any(|&((y1, m1, d1), (y2, m2, d2))| {
+        Utc.ymd(y1, m1, d1).and_hms(0, 0, 0) <= commit.date
+            && commit.date < Utc.ymd(y2, m2, d2).and_hms(0, 0, 0)
+    })
+}
+
+/// The cargo used in place of those in `BROKEN_CARGO`: that of the latest
+/// commit on master. Looked up once per run and shared by every sysroot,
+/// since listing the commits up to master walks the whole history.
+fn known_good_cargo(config: &Config) -> Result<String> {
+    static KNOWN_GOOD_CARGO: Mutex<Option<String>> = Mutex::new(None);
+    let mut known_good = KNOWN_GOOD_CARGO.lock().unwrap();
+    if known_good.is_none() {
+        *known_good = Some(::get_commits(::EPOCH_COMMIT, "master", config)?.pop().unwrap().sha);
+    }
+    Ok(known_good.clone().unwrap())
+}
+
+impl SysrootDownload {
+    fn new(commit: &Commit, triple: &str, save_download: bool, config: &Config) -> Result<Self> {
+        let sha: &str = &commit.sha;
+
+        let has_cargo = config.components.iter().any(|c| c == "cargo");
+        let cargo_sha = if let Some(ref cargo_sha) = config.cargo_sha {
+            cargo_sha.clone()
+        } else if has_cargo && config.channel == Channel::Nightly && has_broken_cargo(commit) {
+            let cargo_sha = known_good_cargo(config)?;
+            info!("cargo of {} is known to be broken, using cargo from {}", sha, cargo_sha);
+            cargo_sha
+        } else {
+            sha.to_string()
+        };
+        let used_fallback_cargo = cargo_sha != sha;
+        let std_sha = config.std_sha.clone().unwrap_or_else(|| sha.to_string());
+
+        fs::create_dir_all(&config.cache_dir)?;
+
+        Ok(SysrootDownload {
+            commit: commit.clone(),
+            directory: config.cache_dir.clone(),
+            save_download,
+            rust_sha: sha.to_string(),
+            cargo_sha,
+            std_sha,
+            triple: triple.to_string(),
+            sysroot_dir: config.sysroot_dir.clone(),
+            retries: config.retries,
+            try_build: config.try_build,
+            channel: config.channel,
+            mirrors: config.mirrors.clone(),
+            offline: config.offline,
+            components: config.components.clone(),
+            progress: config.progress,
+            free_space_factor: config.free_space_factor,
+            pass_env: config.pass_env.clone(),
+            env_overrides: config.env_overrides.iter().filter(|over| over.applies_to(commit)).cloned().collect(),
+            rustc_flags: config.rustc_flags.clone(),
+            used_fallback_cargo,
+            client: client(),
+            auth: ArtifactAuth::from_env(&config.mirrors),
+            fetched: Vec::new(),
+        })
+    }
+
+    fn into_sysroot(self, is_saving_sysroot: bool, interrupt_cleanup: InterruptCleanup,
+                    lock: SysrootLock) -> Result<Sysroot> {
+        let cargo = self.cargo_path()?;
+        LIVE_SYSROOTS.lock().unwrap().extend(vec![self.rust_sha.clone(), self.cargo_sha.clone()]);
+        Ok(Sysroot {
+            rustc: self.bin_path("rustc", "rustc").canonicalize()
+                .chain_err(|| format!("failed to canonicalize rustc path for {}", self.rust_sha))?,
+            rustdoc: self.bin_path("rustc", "rustdoc").canonicalize()
+                .chain_err(|| format!("failed to canonicalize rustdoc path for {}", self.rust_sha))?,
+            cargo,
+            sha: self.rust_sha,
+            commit: self.commit,
+            cache_dir: self.directory,
+            preserve: self.save_download,
+            triple: self.triple,
+            cargo_sha: self.cargo_sha,
+            std_sha: self.std_sha,
+            used_fallback_cargo: self.used_fallback_cargo,
+            is_saving_sysroot,
+            output_dir: None,
+            pass_env: self.pass_env.clone(),
+            env_overrides: self.env_overrides.clone(),
+            rustc_flags: self.rustc_flags.clone(),
+            downloaded_from: Vec::new(),
+            _interrupt_cleanup: interrupt_cleanup,
+            _lock: lock,
+        })
+    }
+
+    /// The directory of this commit's sysroot in `Config::sysroot_dir`, if
+    /// it's there and has all the components to install: rustc under
+    /// `<sha>/rustc`, with the std for the triple if rust-std is one of them,
+    /// and cargo under `<cargo sha>/cargo`. A std from another commit (see
+    /// `Config::std_sha`) can't be had from there, so the sysroot is then
+    /// installed the usual way.
+    fn predownloaded(&self) -> Option<PathBuf> {
+        let dir = self.sysroot_dir.as_ref()?.join(&self.rust_sha);
+        if !dir.join("rustc").join("bin").join(exe("rustc")).exists() {
+            return None;
+        }
+        if self.components.iter().any(|c| c == "rust-std") {
+            let lib = dir.join(format!("rustc/lib/rustlib/{}/lib", self.triple));
+            if self.std_sha != self.rust_sha || !lib.is_dir() {
+                debug!("{} has no rust-std from {} for {}", dir.display(), self.std_sha, self.triple);
+                return None;
+            }
+        }
+        if self.has_cargo() && !self.predownloaded_cargo().exists() {
+            debug!("{} has no cargo from {}", self.sysroot_dir.as_ref()?.display(), self.cargo_sha);
+            return None;
+        }
+        Some(dir)
+    }
+
+    fn predownloaded_cargo(&self) -> PathBuf {
+        self.sysroot_dir.as_ref().unwrap().join(&self.cargo_sha).join("cargo").join("bin").join(exe("cargo"))
+    }
+
+    /// Makes a sysroot out of the one in `dir`, as found by `predownloaded`.
+    /// It's left where it is, so only the scratch directory for this commit
+    /// in the cache directory is removed when it's dropped.
+    fn into_predownloaded(self, dir: &Path, is_saving_sysroot: bool, interrupt_cleanup: InterruptCleanup,
+                          lock: SysrootLock) -> Result<Sysroot> {
+        let canonicalize = |path: PathBuf| {
+            path.canonicalize().chain_err(|| format!("failed to canonicalize {}", path.display()))
+        };
+        let bin = dir.join("rustc").join("bin");
+        let cargo = if self.has_cargo() { Some(canonicalize(self.predownloaded_cargo())?) } else { None };
+        LIVE_SYSROOTS.lock().unwrap().extend(vec![self.rust_sha.clone(), self.cargo_sha.clone()]);
+        Ok(Sysroot {
+            rustc: canonicalize(bin.join(exe("rustc")))?,
+            rustdoc: canonicalize(bin.join(exe("rustdoc")))?,
+            cargo,
+            sha: self.rust_sha,
+            commit: self.commit,
+            cache_dir: self.directory,
+            preserve: self.save_download,
+            triple: self.triple,
+            cargo_sha: self.cargo_sha,
+            std_sha: self.std_sha,
+            used_fallback_cargo: self.used_fallback_cargo,
+            is_saving_sysroot,
+            output_dir: None,
+            pass_env: self.pass_env.clone(),
+            env_overrides: self.env_overrides.clone(),
+            rustc_flags: self.rustc_flags.clone(),
+            downloaded_from: Vec::new(),
+            _interrupt_cleanup: interrupt_cleanup,
+            _lock: lock,
+        })
+    }
+
+    /// Registers the directories the sysroot's `Drop` is going to remove to
+    /// be removed if the process is interrupted, from the start of the
+    /// installation on.
+    fn interrupt_cleanup(&self, is_saving_sysroot: bool) -> InterruptCleanup {
+        let mut paths = Vec::new();
+        if !is_saving_sysroot {
+            paths.push(self.directory.join(&self.rust_sha));
+            if self.cargo_sha == self.rust_sha {
+                paths.push(self.cargo_dir());
+            }
+        }
+        InterruptCleanup::new(paths)
+    }
+
+    fn has_cargo(&self) -> bool {
+        self.components.iter().any(|c| c == "cargo")
+    }
+
+    /// The canonical path of the installed cargo, or `None` if cargo isn't
+    /// one of the components to install.
+    fn cargo_path(&self) -> Result<Option<PathBuf>> {
+        if !self.has_cargo() {
+            return Ok(None);
+        }
+        self.check_cargo()?;
+        let path = self.bin_path("cargo", "cargo").canonicalize()
+            .chain_err(|| format!("failed to canonicalize cargo path for {}", self.cargo_sha))?;
+        Ok(Some(path))
+    }
+
+    /// The path of the executable `name` extracted from `component`.
+    fn bin_path(&self, component: &str, name: &str) -> PathBuf {
+        let dir = if component == "cargo" { self.cargo_dir() } else { self.directory.join(&self.rust_sha) };
+        dir.join(component).join("bin").join(exe(name))
+    }
+
+    fn module<'a>(&'a self, module: &str) -> Module<'a> {
+        Module {
+            variant: match module {
+                "cargo" => ModuleVariant::Cargo,
+                "rustc" => ModuleVariant::Rustc,
+                "rust-std" => ModuleVariant::Std,
+                _ => panic!("unknown module variant: {}", module),
+            },
+            sysroot: self,
+        }
+    }
+
+    fn get_module(&self, module: &str) -> Result<(Vec<PathBuf>, Option<DownloadedFrom>)> {
+        if module == "cargo" {
+            return Ok((Vec::new(), self.get_cargo()?));
+        }
+        self.module(module).get(&self.directory.join(&self.rust_sha))
+    }
+
+    /// How `module` is listed in the `EXTRACTED_MARKER`. rust-std is
+    /// per target, so a sysroot may have it for some targets but not others,
+    /// and it's listed along with its commit if that's not the sysroot's.
+    fn extracted_name(&self, module: &str) -> String {
+        if module == "rust-std" && self.std_sha != self.rust_sha {
+            format!("rust-std-{}@{}", self.triple, self.std_sha)
+        } else if module == "rust-std" {
+            format!("rust-std-{}", self.triple)
+        } else {
+            module.to_string()
+        }
+    }
+
+    fn extracted_marker(&self) -> PathBuf {
+        self.directory.join(&self.rust_sha).join(EXTRACTED_MARKER)
+    }
+
+    /// The modules which were completely extracted into the sysroot, as
+    /// named by `extracted_name`, or `None` if they were extracted in another
+    /// `EXTRACTION_FORMAT` (including before there was one) and can't be used.
+    fn extracted_modules(&self) -> Option<Vec<String>> {
+        let marker = match fs::read_to_string(self.extracted_marker()) {
+            Ok(marker) => marker,
+            Err(_) => return Some(Vec::new()),
+        };
+        let mut lines = marker.lines();
+        if lines.next() != Some(&format!("format {}", EXTRACTION_FORMAT)[..]) {
+            return None;
+        }
+        Some(lines.map(String::from).collect())
+    }
+
+    fn mark_extracted(&self, modules: &[String]) -> Result<()> {
+        let marker = self.extracted_marker();
+        let partial = partial_path(&marker);
+        let mut contents = format!("format {}\n", EXTRACTION_FORMAT);
+        for module in modules {
+            contents.push_str(module);
+            contents.push('\n');
+        }
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, &marker).chain_err(|| format!("failed to write {}", marker.display()))
+    }
+
+    /// Where cargo is extracted. Many sysroots use the same cargo, e.g. a
+    /// fallback one, so it's shared between them rather than extracted into
+    /// each sysroot.
+    fn cargo_dir(&self) -> PathBuf {
+        cargo_dir(&self.directory, &self.cargo_sha)
+    }
+
+    /// Extracts cargo into `cargo_dir`, unless it's there already. It's
+    /// extracted elsewhere first and then moved into place, so that the
+    /// directory existing means that cargo is complete.
+    fn get_cargo(&self) -> Result<Option<DownloadedFrom>> {
+        let dir = self.cargo_dir();
+        let mut downloaded_from = None;
+        if dir.exists() {
+            debug!("reusing cargo from {}", dir.display());
+        } else {
+            fs::create_dir_all(dir.parent().unwrap())?;
+            let partial = partial_path(&dir);
+            let result = self.module("cargo").get(&partial)
+                .and_then(|(_, from)| {
+                    downloaded_from = from;
+                    fs::rename(&partial, &dir).or_else(|err| {
+                        // Another process may have just extracted the same cargo.
+                        if dir.exists() { Ok(()) } else { Err(err.into()) }
+                    })
+                });
+            if partial.exists() {
+                let _ = fs::remove_dir_all(&partial);
+            }
+            result?;
+        }
+        cache::touch(&dir)?;
+        Ok(downloaded_from)
+    }
+
+    /// Fails with a list of every module of `modules` that has no archive
+    /// saved in the cache directory, so that an offline run stops before
+    /// extracting anything.
+    fn check_offline(&self, modules: &[&str]) -> Result<()> {
+        let missing = modules.iter()
+            .filter(|module| **module != "cargo" || !self.cargo_dir().exists())
+            .map(|module| self.module(module))
+            .filter(|module| !module.is_saved())
+            .map(|module| format!("{} for {}", module.variant, module.sha()))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            bail!("running offline, but these archives are missing from {}: {}",
+                self.directory.display(), missing.join(", "));
+        }
+        Ok(())
+    }
+
+    fn get_and_extract(&self, module: &str) -> Result<Option<DownloadedFrom>> {
+        let (to_link, downloaded_from) = self.get_module(module)?;
+        self.link_std_dylibs(to_link)?;
+        Ok(downloaded_from)
+    }
+
+    /// Downloads and extracts `modules`, running up to `jobs` of them at once.
+    ///
+    /// The modules all unpack into `cache/<sha>`, and rust-std into rustc's
+    /// tree at that, under `rustc/lib/rustlib/<triple>`. They don't ship any of
+    /// the same files though, and `move_into` copes with directories another
+    /// module moved in meanwhile. What does depend on the order is the std
+    /// dylibs, which get hard-linked from `rustc/lib` and so can only be
+    /// linked once rustc is fully extracted. They're collected from every
+    /// module's thread and only linked after all of those were joined.
+    fn get_modules(&self, modules: &[&str], jobs: usize) -> Result<Vec<DownloadedFrom>> {
+        let mut to_link = Vec::new();
+        let mut downloaded_from = Vec::new();
+        for chunk in modules.chunks(cmp::max(jobs, 1)) {
+            let handles = chunk.iter().map(|module| {
+                let download = self.clone();
+                let module = module.to_string();
+                thread::spawn(move || download.get_module(&module))
+            }).collect::<Vec<_>>();
+
+            // Join every thread before reporting an error, so that nothing is
+            // still writing into the cache once we return.
+            let mut first_err = None;
+            for handle in handles {
+                let result = handle.join()
+                    .unwrap_or_else(|_| Err("module download thread panicked".into()));
+                match result {
+                    Ok((dylibs, from)) => {
+                        to_link.extend(dylibs);
+                        downloaded_from.extend(from);
+                    }
+                    Err(err) => if first_err.is_none() {
+                        first_err = Some(err);
+                    },
+                }
+            }
+            if let Some(err) = first_err {
+                return Err(err);
+            }
+        }
+        self.link_std_dylibs(to_link)?;
+        Ok(downloaded_from)
+    }
+
+    /// Extracts `module` from `reader` into `unpack_into`, adding the time
+    /// it takes to the download stats. If `reader` is a download, the time
+    /// spent waiting for it is left out, having been counted as downloading.
+    fn extract<'b>(&self, module: &Module, reader: Box<dyn Read + 'b>, unpack_into: &Path) -> Result<Vec<PathBuf>> {
+        let start = Instant::now();
+        let read_before = READ_TIME.with(Cell::get);
+        let result = self.unpack(module, reader, unpack_into);
+        let reading = READ_TIME.with(Cell::get) - read_before;
+        EXTRACT_NANOS.fetch_add((start.elapsed() - reading).as_nanos() as u64, Ordering::SeqCst);
+        result
+    }
+
+    fn unpack<'b>(&self, module: &Module, reader: Box<dyn Read + 'b>, unpack_into: &Path) -> Result<Vec<PathBuf>> {
         let is_std = module.variant == ModuleVariant::Std;
         let mut archive = Archive::new(reader);
-        let std_prefix = format!("rust-std-{}/lib/rustlib", self.triple);
 
         let mut to_link = Vec::new();
 
-        let unpack_into = self.directory.join(&self.rust_sha);
-
+        let mut container = None;
         for entry in archive.entries()? {
             let mut entry = entry?;
             let path = entry.path()?.into_owned();
-            let mut components = path.components();
-            assert!(components.next().is_some(), "strip container directory");
-            let path = components.as_path();
+            if container.is_none() {
+                container = container_dir(&path, module.variant);
+            }
+            let path = match container.as_ref().and_then(|container| path.strip_prefix(container).ok()) {
+                Some(path) if path.as_os_str().is_empty() => continue,
+                Some(path) => path,
+                None => {
+                    debug!("skipping {}, which is outside the container directory", path.display());
+                    continue;
+                }
+            };
 
             let path = if is_std {
-                if let Ok(path) = path.strip_prefix(&std_prefix) {
-                    if path.extension() == Some(OsStr::new("dylib")) {
-                        to_link.push(path.to_owned());
-                        continue;
-                    } else {
-                        Path::new("rustc/lib/rustlib").join(path)
+                match std_destination(path, &self.triple) {
+                    Some(path) => {
+                        if is_dylib(&path) {
+                            to_link.push(path.strip_prefix("rustc/lib/rustlib").unwrap().to_owned());
+                        }
+                        path
                     }
-                } else {
-                    continue;
+                    None => continue,
                 }
             } else {
                 path.into()
@@ -331,19 +2110,368 @@ impl SysrootDownload {
                         path.display()))?;
             entry.unpack(path)?;
         }
+        if container.is_none() {
+            bail!("the {} archive has no {}-* directory to extract", module.variant, module.variant);
+        }
+
+        Ok(to_link)
+    }
 
-        let link_dst_prefix = unpack_into.join(format!("rustc/lib/rustlib/{}/lib", self.triple));
+    /// Replaces the std dylibs extracted from rust-std with hard links to the
+    /// identical ones rustc ships with. Targets other than the host, such as
+    /// musl ones, may have dylibs rustc doesn't ship; those keep their copy.
+    fn link_std_dylibs(&self, to_link: Vec<PathBuf>) -> Result<()> {
+        let unpack_into = self.directory.join(&self.rust_sha);
         let link_src_prefix = format!("{}/lib", self.triple);
         for path in to_link {
             let src = unpack_into.join("rustc/lib").join(path.strip_prefix(&link_src_prefix)
                 .chain_err(|| format!("stripping prefix from: {:?}", path))?);
-            let dst = link_dst_prefix.join(&path);
-            fs::create_dir_all(&dst.parent().unwrap())
-                .chain_err(|| format!("could not create intermediate directories for {}", dst.display()))?;
+            let dst = unpack_into.join("rustc/lib/rustlib").join(&path);
+            if !src.exists() {
+                debug!("{} is not shipped with rustc, keeping {}", src.display(), dst.display());
+                continue;
+            }
             debug!("linking {} to {}", src.display(), dst.display());
+            fs::remove_file(&dst)?;
             fs::hard_link(src, dst)?;
         }
 
         Ok(())
     }
+
+    /// Checks that the chosen cargo was actually installed, which may not be
+    /// the case for a cargo picked with `Config::cargo_sha`.
+    fn check_cargo(&self) -> Result<()> {
+        let cargo = self.bin_path("cargo", "cargo");
+        if !cargo.exists() {
+            bail!("cargo from {} was not installed into {}", self.cargo_sha, cargo.display());
+        }
+        Ok(())
+    }
+
+    /// Checks that rust-std put a standard library for the target into the
+    /// sysroot. Its layout differs between targets, so getting it wrong would
+    /// otherwise only show up once the test fails to find `core`.
+    fn check_std(&self) -> Result<()> {
+        let lib = self.directory.join(&self.rust_sha)
+            .join(format!("rustc/lib/rustlib/{}/lib", self.triple));
+        let has_core = fs::read_dir(&lib).map(|entries| {
+            entries.filter_map(|entry| entry.ok())
+                .any(|entry| entry.file_name().to_string_lossy().starts_with("libcore-"))
+        }).unwrap_or(false);
+        if !has_core {
+            bail!("rust-std for {} did not install libcore into {}", self.triple, lib.display());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bzip2::write::BzEncoder;
+    use xz2::write::XzEncoder;
+    use std::sync::{Arc, Barrier};
+
+    /// Entries as laid out in rust-std-nightly-x86_64-unknown-linux-musl.tar.xz,
+    /// relative to its container directory, with most of the rlibs left out.
+    /// The target has no std dylib, only static libraries.
+    const MUSL_STD_LISTING: &[&str] = &[
+        "components",
+        "install.sh",
+        "rust-installer-version",
+        "rust-std-x86_64-unknown-linux-musl/manifest.in",
+        "rust-std-x86_64-unknown-linux-musl/lib/rustlib/x86_64-unknown-linux-musl/lib/liballoc-7f4a0c4a1b5d8b2e.rlib",
+        "rust-std-x86_64-unknown-linux-musl/lib/rustlib/x86_64-unknown-linux-musl/lib/libcore-3b2a5e1f0c9d7a64.rlib",
+        "rust-std-x86_64-unknown-linux-musl/lib/rustlib/x86_64-unknown-linux-musl/lib/liblibc-a0d6f1e9c3b84e27.rlib",
+        "rust-std-x86_64-unknown-linux-musl/lib/rustlib/x86_64-unknown-linux-musl/lib/libstd-95e2c7b1d04f3a68.rlib",
+        "rust-std-x86_64-unknown-linux-musl/lib/rustlib/x86_64-unknown-linux-musl/lib/libunwind-5c8d2f7e1a6b3094.rlib",
+        "rust-std-x86_64-unknown-linux-musl/lib/rustlib/x86_64-unknown-linux-musl/lib/self-contained/crt1.o",
+        "rust-std-x86_64-unknown-linux-musl/lib/rustlib/x86_64-unknown-linux-musl/lib/self-contained/crti.o",
+        "rust-std-x86_64-unknown-linux-musl/lib/rustlib/x86_64-unknown-linux-musl/lib/self-contained/libc.a",
+        "rust-std-x86_64-unknown-linux-musl/lib/rustlib/x86_64-unknown-linux-musl/lib/self-contained/libunwind.a",
+    ];
+
+    #[test]
+    fn std_destination_musl() {
+        let triple = "x86_64-unknown-linux-musl";
+        let kept: Vec<_> = MUSL_STD_LISTING.iter()
+            .filter_map(|path| std_destination(Path::new(path), triple))
+            .collect();
+        let lib = Path::new("rustc/lib/rustlib/x86_64-unknown-linux-musl/lib");
+        assert_eq!(kept, vec![
+            lib.join("liballoc-7f4a0c4a1b5d8b2e.rlib"),
+            lib.join("libcore-3b2a5e1f0c9d7a64.rlib"),
+            lib.join("liblibc-a0d6f1e9c3b84e27.rlib"),
+            lib.join("libstd-95e2c7b1d04f3a68.rlib"),
+            lib.join("libunwind-5c8d2f7e1a6b3094.rlib"),
+            lib.join("self-contained/crt1.o"),
+            lib.join("self-contained/crti.o"),
+            lib.join("self-contained/libc.a"),
+            lib.join("self-contained/libunwind.a"),
+        ]);
+    }
+
+    /// A tar archive of `files`, each containing its own path.
+    fn tar_of(files: &[&str]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for path in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(path.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, path.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// The paths in `archive` relative to its container directory, skipping
+    /// those outside of it, as `SysrootDownload::unpack` does.
+    fn stripped_paths<R: Read>(archive: R, variant: ModuleVariant) -> Vec<PathBuf> {
+        let mut archive = Archive::new(archive);
+        let mut container = None;
+        let mut paths = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let path = entry.unwrap().path().unwrap().into_owned();
+            if container.is_none() {
+                container = container_dir(&path, variant);
+            }
+            if let Some(path) = container.as_ref().and_then(|container| path.strip_prefix(container).ok()) {
+                paths.push(path.to_owned());
+            }
+        }
+        paths
+    }
+
+    #[test]
+    fn container_dir_nested() {
+        let archive = tar_of(&[
+            "dist/rustc-nightly-x86_64-unknown-linux-gnu/components",
+            "dist/rustc-nightly-x86_64-unknown-linux-gnu/rustc/bin/rustc",
+            "dist/rustc-nightly-x86_64-unknown-linux-gnu/rustc/lib/librustc_driver.so",
+            "dist/README.md",
+        ]);
+        assert_eq!(stripped_paths(&archive[..], ModuleVariant::Rustc), vec![
+            PathBuf::from("components"),
+            PathBuf::from("rustc/bin/rustc"),
+            PathBuf::from("rustc/lib/librustc_driver.so"),
+        ]);
+    }
+
+    #[test]
+    fn container_dir_paths() {
+        let path = Path::new("dist/rust-std-nightly-x86_64-unknown-linux-musl/components");
+        assert_eq!(container_dir(path, ModuleVariant::Std),
+                   Some(PathBuf::from("dist/rust-std-nightly-x86_64-unknown-linux-musl")));
+        assert_eq!(container_dir(Path::new("cargo-nightly-x86_64-unknown-linux-gnu/cargo/bin/cargo"),
+                                 ModuleVariant::Cargo),
+                   Some(PathBuf::from("cargo-nightly-x86_64-unknown-linux-gnu")));
+        // rustc's prefix must not match rust-std's container.
+        assert_eq!(container_dir(path, ModuleVariant::Rustc), None);
+    }
+
+    #[test]
+    fn decompress_bz2() {
+        let files = ["rustc-nightly-x86_64-unknown-linux-gnu/components",
+                     "rustc-nightly-x86_64-unknown-linux-gnu/rustc/bin/rustc"];
+        let mut encoder = BzEncoder::new(Vec::new(), bzip2::Compression::Default);
+        encoder.write_all(&tar_of(&files)).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(entries_of(decompress(&compressed[..], "bz2").unwrap()), files);
+    }
+
+    #[test]
+    fn decompress_concatenated_xz() {
+        // The archive is split across two xz streams, as parallel compressors
+        // produce, with the first entry spanning both.
+        let files = ["rustc-nightly-x86_64-unknown-linux-gnu/components",
+                     "rustc-nightly-x86_64-unknown-linux-gnu/rustc/bin/rustc",
+                     "rustc-nightly-x86_64-unknown-linux-gnu/rustc/lib/librustc_driver.so"];
+        let archive = tar_of(&files);
+        let mut compressed = Vec::new();
+        for part in &[&archive[..100], &archive[100..]] {
+            let mut encoder = XzEncoder::new(Vec::new(), 6);
+            encoder.write_all(part).unwrap();
+            compressed.extend(encoder.finish().unwrap());
+        }
+
+        assert_eq!(entries_of(decompress(&compressed[..], "xz").unwrap()), files);
+    }
+
+    /// The paths of the entries in `archive`, checking that each contains
+    /// its own path, as those from `tar_of` do.
+    fn entries_of<R: Read>(archive: R) -> Vec<String> {
+        let mut archive = Archive::new(archive);
+        let mut paths = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, path);
+            paths.push(path);
+        }
+        paths
+    }
+
+    #[test]
+    fn extension_served_gz_for_xz() {
+        let url = "https://example.com/rustc-nightly-x86_64-unknown-linux-gnu.tar.xz";
+        // Redirected to a gzipped archive.
+        assert_eq!(extension_served(None, None, "https://example.com/rustc.tar.gz", "xz"), "gz");
+        // Served under the .xz URL, as told by the headers.
+        assert_eq!(extension_served(Some("application/gzip"), None, url, "xz"), "gz");
+        assert_eq!(extension_served(Some("application/x-gzip; charset=binary"), None, url, "xz"), "gz");
+        assert_eq!(extension_served(Some("application/octet-stream"), Some("gzip"), url, "xz"), "gz");
+        assert_eq!(extension_served(Some("application/octet-stream"), None, url, "xz"), "xz");
+    }
+
+    #[test]
+    fn extension_served_xz_for_gz() {
+        let url = "https://example.com/rustc-nightly-x86_64-unknown-linux-gnu.tar.gz";
+        assert_eq!(extension_served(None, None, "https://example.com/rustc.tar.xz", "gz"), "xz");
+        assert_eq!(extension_served(Some("application/x-xz"), None, url, "gz"), "xz");
+        assert_eq!(extension_served(Some("Application/XZ"), None, url, "gz"), "xz");
+        assert_eq!(extension_served(None, None, url, "gz"), "gz");
+    }
+
+    #[test]
+    fn extension_served_precedence() {
+        // A redirect wins over the headers, and the encoding over the type.
+        let url = "https://example.com/rustc.tar.zst";
+        assert_eq!(extension_served(Some("application/gzip"), Some("xz"), url, "xz"), "zst");
+        let url = "https://example.com/rustc";
+        assert_eq!(extension_served(Some("application/gzip"), Some("bzip2"), url, "xz"), "bz2");
+    }
+
+    #[test]
+    fn move_into_merges() {
+        let root = env::temp_dir().join(format!("bisect-rust-move-{}", process::id()));
+        let (from, to) = (root.join("from"), root.join("to"));
+        fs::create_dir_all(from.join("rustc/lib/rustlib")).unwrap();
+        fs::create_dir_all(to.join("rustc/lib")).unwrap();
+        fs::write(from.join("rustc/lib/rustlib/libstd.rlib"), "std").unwrap();
+        fs::write(from.join("rustc/lib/librustc.so"), "new").unwrap();
+        fs::write(to.join("rustc/lib/librustc.so"), "old").unwrap();
+        fs::write(to.join("rustc/lib/libother.so"), "other").unwrap();
+
+        move_into(&from, &to).unwrap();
+        let read = |path: &str| fs::read_to_string(to.join(path)).unwrap();
+        assert_eq!(read("rustc/lib/rustlib/libstd.rlib"), "std");
+        assert_eq!(read("rustc/lib/librustc.so"), "new");
+        assert_eq!(read("rustc/lib/libother.so"), "other");
+        assert!(!from.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn move_into_concurrently() {
+        let root = env::temp_dir().join(format!("bisect-rust-move-concurrent-{}", process::id()));
+        let to = root.join("sysroot");
+        for round in 0..200 {
+            // Like rustc and rust-std, both have a `rustc/lib` to merge.
+            let staging = ["rustc", "rust-std"].iter().map(|module| {
+                let from = root.join(format!("{}-{}", module, round));
+                fs::create_dir_all(from.join("rustc/lib/rustlib")).unwrap();
+                fs::write(from.join(format!("rustc/lib/{}.so", module)), module).unwrap();
+                fs::write(from.join(format!("rustc/lib/rustlib/{}.rlib", module)), module).unwrap();
+                from
+            }).collect::<Vec<_>>();
+            let barrier = Arc::new(Barrier::new(staging.len()));
+            let threads = staging.into_iter().map(|from| {
+                let (to, barrier) = (to.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    move_into(&from, &to)
+                })
+            }).collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap().unwrap();
+            }
+            for module in &["rustc", "rust-std"] {
+                assert!(to.join(format!("rustc/lib/{}.so", module)).is_file());
+                assert!(to.join(format!("rustc/lib/rustlib/{}.rlib", module)).is_file());
+            }
+            fs::remove_dir_all(&to).unwrap();
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn std_dylibs_linked_after_rustc() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = env::temp_dir().join(format!("bisect-rust-link-{}", process::id()));
+        let (sha, triple) = ("0123456789abcdef0123456789abcdef01234567", "x86_64-unknown-linux-gnu");
+        let config = Config {
+            cache_dir: root.clone(),
+            jobs: 1,
+            retries: 0,
+            try_build: false,
+            max_cache_size: None,
+            mirrors: Vec::new(),
+            channel: Channel::Nightly,
+            cargo_sha: Some(sha.to_string()),
+            std_sha: None,
+            merge_bots: Vec::new(),
+            local_repo: false,
+            offline: true,
+            sysroot_dir: None,
+            components: vec!["rustc".to_string(), "rust-std".to_string()],
+            refresh_commits: false,
+            progress: false,
+            free_space_factor: 0,
+            pass_env: Vec::new(),
+            env_overrides: Vec::new(),
+            rustc_flags: Vec::new(),
+        };
+        let commit = Commit { sha: sha.to_string(), date: Utc::now(), summary: String::new() };
+        let download = SysrootDownload::new(&commit, triple, false, &config).unwrap();
+
+        // Saved in the cache, so that they're extracted without downloading.
+        let std_dylib = format!("{}/lib/libstd-0123.so", triple);
+        let archives = [
+            ("rustc", vec![
+                "rustc-nightly-x86_64-unknown-linux-gnu/rustc/bin/rustc".to_string(),
+                "rustc-nightly-x86_64-unknown-linux-gnu/rustc/lib/libstd-0123.so".to_string(),
+            ]),
+            ("rust-std", vec![
+                format!("rust-std-nightly-{0}/rust-std-{0}/lib/rustlib/{1}", triple, std_dylib),
+            ]),
+        ];
+        for &(module, ref files) in &archives {
+            let files = files.iter().map(|file| &file[..]).collect::<Vec<_>>();
+            let mut encoder = XzEncoder::new(Vec::new(), 6);
+            encoder.write_all(&tar_of(&files)).unwrap();
+            let path = root.join(format!("{}-{}-{}.tar.xz", sha, triple, module));
+            fs::write(&path, encoder.finish().unwrap()).unwrap();
+            fs::write(checksum_path(&path), sha256_file(&path).unwrap()).unwrap();
+        }
+
+        // rust-std finishing first, its dylibs can only be linked after rustc.
+        let (to_link, _) = download.get_module("rust-std").unwrap();
+        assert_eq!(to_link, vec![PathBuf::from(&std_dylib)]);
+        download.get_module("rustc").unwrap();
+        download.link_std_dylibs(to_link).unwrap();
+
+        let sysroot = root.join(sha).join("rustc/lib");
+        let src = fs::metadata(sysroot.join("libstd-0123.so")).unwrap();
+        let dst = fs::metadata(sysroot.join("rustlib").join(&std_dylib)).unwrap();
+        assert_eq!((src.dev(), src.ino()), (dst.dev(), dst.ino()));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn channel_from_str() {
+        assert_eq!("beta".parse::<Channel>().unwrap(), Channel::Beta);
+        assert!("dev".parse::<Channel>().is_err());
+    }
+
+    #[test]
+    fn std_destination_other_triple() {
+        assert!(MUSL_STD_LISTING.iter()
+            .all(|path| std_destination(Path::new(path), "x86_64-unknown-linux-gnu").is_none()));
+    }
 }
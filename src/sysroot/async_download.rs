@@ -0,0 +1,225 @@
+//! Fetching the archives of a sysroot's modules concurrently with the async
+//! reqwest client on a tokio runtime, behind the `async-download` feature.
+//!
+//! The archives are saved in the cache directory and handed to `Module::get`,
+//! which extracts them without looking them up again, so only the network
+//! part differs from the default path. Progress is reported and free space
+//! checked for like there, but a fetch is neither retried nor resumed: a
+//! module which fails to be fetched this way is left for `Module::get` to
+//! download as usual, with its retries and backoff, and resuming from where
+//! an interrupted download of its own left off.
+
+use std::cmp;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use futures::{future, stream, Future, Stream};
+use futures::future::Loop;
+use futures::sync::oneshot;
+use hex;
+use reqwest::async::{Client, Response};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, USER_AGENT};
+use sha2::{Digest, Sha256};
+use tokio::runtime::Runtime;
+
+use super::{archive_extension, check_free_space, checksum_path, extension_served, partial_path, store_archive,
+            DownloadedFrom, Progress, SysrootDownload, ARCHIVE_EXTENSIONS, DOWNLOADS, DOWNLOADED_BYTES,
+            DOWNLOAD_NANOS, DOWNLOAD_TIMEOUT, FALLBACK_DOWNLOADS};
+
+use errors::{Error, Result};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+/// The async client all fetches go through, like the blocking `client()`,
+/// so that connections are reused across sysroots.
+fn client() -> Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static("rust-lang/bisect-rust"));
+        Client::builder()
+            .timeout(DOWNLOAD_TIMEOUT)
+            .default_headers(headers)
+            .build()
+            .expect("failed to create the async HTTP client")
+    }).clone()
+}
+
+/// The runtime the fetches run on, started once for the whole run rather than
+/// for every sysroot. The fetches of sysroots installed at once, e.g. while
+/// prefetching, share it.
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Runtime::new().expect("failed to start the tokio runtime"))
+}
+
+// An archive to fetch, worked out from its `Module` up front since the
+// futures can't borrow that.
+struct Fetch {
+    module: String,
+    /// The URLs to try in order, along with the token to send to each.
+    urls: Vec<(String, Option<String>)>,
+    /// Where to save the archive, for each extension it may come in.
+    paths: Vec<(&'static str, PathBuf)>,
+    /// The cache directory and `Config::free_space_factor`, to check there's
+    /// room for the archive once it's extracted.
+    free_space: (PathBuf, u64),
+    progress: bool,
+}
+
+impl SysrootDownload {
+    /// Fetches the archives of those of `modules` which aren't in the cache
+    /// directory yet, up to `jobs` at once over a single client, and saves
+    /// them there, recording them in `fetched` for `Module::get`.
+    pub(super) fn fetch_async(&mut self, modules: &[&str], jobs: usize) -> Result<()> {
+        if self.offline {
+            return Ok(());
+        }
+        let fetches = modules.iter()
+            .filter(|module| **module != "cargo" || !self.cargo_dir().exists())
+            .map(|module| self.module(module))
+            .filter(|module| !module.is_saved())
+            .map(|module| Fetch {
+                module: module.variant.to_string(),
+                urls: module.urls().into_iter().map(|url| {
+                    let token = self.auth.as_ref().filter(|auth| auth.applies_to(&url)).map(|auth| auth.token.clone());
+                    (url, token)
+                }).collect(),
+                paths: ARCHIVE_EXTENSIONS.iter().map(|&extension| (extension, module.archive_path(extension))).collect(),
+                free_space: (self.directory.clone(), self.free_space_factor),
+                progress: self.progress,
+            })
+            .collect::<Vec<_>>();
+        if fetches.is_empty() {
+            return Ok(());
+        }
+
+        let client = client();
+        let start = Instant::now();
+        let fetched = stream::iter_ok::<_, Error>(fetches)
+            .map(move |fetch| {
+                let module = fetch.module.clone();
+                fetch_one(client.clone(), fetch).then(move |result| Ok(match result {
+                    Ok(fetched) => fetched,
+                    Err(err) => {
+                        warn!("fetching {} failed, falling back to a blocking download: {}", module, err);
+                        None
+                    }
+                }))
+            })
+            .buffer_unordered(cmp::max(jobs, 1))
+            .filter_map(|fetched| fetched)
+            .collect();
+        let fetched = oneshot::spawn(fetched, &runtime().executor()).wait()?;
+        DOWNLOAD_NANOS.fetch_add(start.elapsed().as_nanos() as u64, Ordering::SeqCst);
+        DOWNLOADS.fetch_add(fetched.len(), Ordering::SeqCst);
+        for (_, from) in &fetched {
+            info!("downloaded {} from {}", from.module, from.url);
+            if from.fallback {
+                FALLBACK_DOWNLOADS.lock().unwrap().push(from.clone());
+            }
+        }
+        self.fetched = fetched;
+        Ok(())
+    }
+}
+
+// Tries the URLs of `fetch` in order, saving the first archive found.
+// Returns where it was saved, or `None` if there's none at any of them.
+fn fetch_one(client: Client, fetch: Fetch) -> BoxFuture<Option<(PathBuf, DownloadedFrom)>> {
+    let Fetch { module, urls, paths, free_space, progress } = fetch;
+    Box::new(future::loop_fn(0, move |i| -> BoxFuture<Loop<Option<(PathBuf, DownloadedFrom)>, usize>> {
+        let (url, token) = match urls.get(i) {
+            Some(url) => url.clone(),
+            None => return Box::new(future::ok(Loop::Break(None))),
+        };
+        let (client, paths, module, free_space) = (client.clone(), paths.clone(), module.clone(), free_space.clone());
+        Box::new(get(&client, &url, token.as_ref()).and_then(move |resp| -> BoxFuture<Loop<_, _>> {
+            if !resp.status().is_success() {
+                return Box::new(future::ok(Loop::Continue(i + 1)));
+            }
+            let from = DownloadedFrom { module, url: url.clone(), fallback: i > 0 };
+            if let Err(err) = check_free_space(&free_space.0, free_space.1, &url, resp.content_length()) {
+                return Box::new(future::err(err));
+            }
+            Box::new(save(client, resp, url, token, paths, progress)
+                .map(move |archive_path| Loop::Break(Some((archive_path, from)))))
+        }))
+    }))
+}
+
+fn get(client: &Client, url: &str, token: Option<&String>) -> BoxFuture<Response> {
+    debug!("requesting: {}", url);
+    let mut req = client.get(url);
+    if let Some(token) = token {
+        req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    Box::new(req.send().map_err(Error::from))
+}
+
+// Writes the archive in `resp` into place in the cache directory, along with
+// its checksum, once it matches the one published alongside it at `url`. It's
+// saved with the extension it was served as, see `extension_served`.
+fn save(client: Client, resp: Response, url: String, token: Option<String>, paths: Vec<(&'static str, PathBuf)>,
+        progress: bool) -> BoxFuture<PathBuf> {
+    let requested = match archive_extension(&url) {
+        Ok(extension) => extension,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let extension = {
+        let header = |name| resp.headers().get(name).and_then(|value| value.to_str().ok());
+        extension_served(header(CONTENT_TYPE), header(CONTENT_ENCODING), resp.url().as_str(), requested)
+    };
+    let archive_path = paths.into_iter().find(|&(e, _)| e == extension).unwrap().1;
+    let progress = if progress { Some(Progress::new(&url, resp.content_length())) } else { None };
+    let partial = partial_path(&archive_path);
+    let file = match File::create(&partial) {
+        Ok(file) => file,
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+    let expected = get(&client, &format!("{}.sha256", url), token.as_ref()).and_then(|resp| -> BoxFuture<_> {
+        if !resp.status().is_success() {
+            return Box::new(future::ok(None));
+        }
+        // The file looks like `<hex digest>  <file name>`.
+        Box::new(resp.into_body().concat2().map_err(Error::from).map(|body| {
+            String::from_utf8_lossy(&body).split_whitespace().next().map(|digest| digest.to_lowercase())
+        }))
+    });
+    let body = resp.into_body().map_err(Error::from).fold((file, Sha256::new(), progress),
+                                                          |(mut file, mut hasher, mut progress), chunk| {
+        file.write_all(&chunk)?;
+        hasher.input(&chunk);
+        DOWNLOADED_BYTES.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+        if let Some(ref mut progress) = progress {
+            progress.advance(chunk.len());
+        }
+        Ok::<_, Error>((file, hasher, progress))
+    });
+    let cleanup = partial.clone();
+    Box::new(body.join(expected).and_then(move |((file, hasher, progress), expected)| {
+        if let Some(mut progress) = progress {
+            progress.advance(0);
+        }
+        let actual = hex::encode(hasher.result());
+        match expected {
+            Some(ref expected) if *expected != actual => {
+                bail!("checksum mismatch for {}: expected {}, got {}", url, expected, actual);
+            }
+            Some(_) => {}
+            None => warn!("no checksum published for {}, skipping verification", url),
+        }
+        file.sync_all()?;
+        drop(file);
+        fs::write(checksum_path(&archive_path), &actual)?;
+        store_archive(&partial, &archive_path, &actual)?;
+        Ok(archive_path)
+    }).or_else(move |err| {
+        let _ = fs::remove_file(&cleanup);
+        Err(err)
+    }))
+}
@@ -1,9 +1,20 @@
 #!/bin/sh
 
+# Invoked as `test.sh` for every commit, or with --stages as `test.sh build`
+# and then as `test.sh run`. It fails if the error message shows up in the
+# output.
+# Exiting with 125 aborts the whole bisection instead.
+
 TEST_DIRECTORY="test"
 ERROR_MESSAGE="internal compiler error"
 
-docker run --rm -v `pwd`:/dir -v `pwd`/$TEST_DIRECTORY:/source \
+case "$1" in
+	build) CARGO_ARGS="test --no-run" ;;
+	*) CARGO_ARGS="test" ;;
+esac
+
+! docker run --rm -v `pwd`:/dir -v `pwd`/$TEST_DIRECTORY:/source \
 	-e RUSTC=/dir/$RUSTC_RELATIVE -e CARGO_RELATIVE -e RUSTDOC=/dir/$RUSTDOC_RELATIVE \
-	bisector bash -c 'cd /source && rm -fr target ; /dir/$CARGO_RELATIVE test' \
+	-e CARGO_ARGS="$CARGO_ARGS" \
+	bisector bash -c 'cd /source && rm -fr target ; /dir/$CARGO_RELATIVE $CARGO_ARGS' \
 	2>&1 | rg -q "$ERROR_MESSAGE"